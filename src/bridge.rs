@@ -0,0 +1,489 @@
+use crate::buffer::DefaultBufferStore;
+use crate::cdc_acm::{LineCoding, SerialState};
+use crate::serial_port::SerialPort;
+use crate::wrapper::forward_usb_class;
+use core::borrow::BorrowMut;
+use core::convert::Infallible;
+use embedded_hal::serial;
+use usb_device::Result;
+use usb_device::class_prelude::*;
+
+/// UART side of a [`UartBridge`]
+pub trait BridgeUart: serial::Read<u8> + serial::Write<u8> {
+    /// Reconfigures the UART for a new line coding. This is called when the bridge is created and
+    /// whenever the line coding changes afterwards.
+    fn set_line_coding(&mut self, line_coding: &LineCoding);
+
+    /// Maps a receive error to the flags reported to the host in a SERIAL_STATE notification,
+    /// usually one of [`FRAMING_ERROR`](SerialState::FRAMING_ERROR),
+    /// [`PARITY_ERROR`](SerialState::PARITY_ERROR) or [`OVERRUN`](SerialState::OVERRUN).
+    fn error_state(&self, error: &<Self as serial::Read<u8>>::Error) -> SerialState;
+}
+
+/// Modem control lines of a [`UartBridge`]
+///
+/// The unit type implements this trait for bridges that don't have any control lines.
+pub trait ControlLines {
+    /// Sets the DTR (data terminal ready) output.
+    fn set_dtr(&mut self, active: bool);
+
+    /// Sets the RTS (request to send) output.
+    fn set_rts(&mut self, active: bool);
+
+    /// Gets the state of the DCD, DSR and ring signal inputs. Any other flags are ignored.
+    fn input_state(&mut self) -> SerialState {
+        SerialState::empty()
+    }
+}
+
+impl ControlLines for () {
+    fn set_dtr(&mut self, _active: bool) {}
+
+    fn set_rts(&mut self, _active: bool) {}
+}
+
+/// Error returned by [`UartBridge::poll`]
+#[derive(Debug)]
+pub enum BridgeError<E> {
+    /// Error from `usb-device`
+    Usb(UsbError),
+
+    /// Error writing to the UART
+    Uart(E),
+}
+
+impl<E> From<UsbError> for BridgeError<E> {
+    fn from(e: UsbError) -> Self {
+        BridgeError::Usb(e)
+    }
+}
+
+/// USB to UART bridge built on a [`SerialPort`].
+///
+/// The bridge copies data between the serial port buffers and the UART, mirrors the line coding
+/// into the UART configuration and DTR/RTS into the control lines, and reports UART receive errors
+/// and input line changes to the host with SERIAL_STATE notifications.
+///
+/// Both directions apply backpressure. Data from the host is only read from the endpoint when
+/// there is room in the read buffer, and the UART is only read when there is room in the write
+/// buffer. A UART that keeps receiving data while the host isn't reading will eventually overrun,
+/// which is reported to the host if the UART reports it.
+///
+/// Line coding and control line requests from the host reach the bridge through `UsbDevice::poll`,
+/// and are applied to the UART by [`poll`](UartBridge::poll). Call it after every
+/// `UsbDevice::poll`, and as often as needed to keep up with the UART.
+pub struct UartBridge<'a, B, U, L = (), RS = DefaultBufferStore, WS = DefaultBufferStore>
+where
+    B: UsbBus,
+    U: BridgeUart,
+    L: ControlLines,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    port: SerialPort<'a, B, RS, WS>,
+    uart: U,
+    lines: L,
+    line_coding: LineCoding,
+    dtr: bool,
+    rts: bool,
+    state: SerialState,
+    events: SerialState,
+}
+
+impl<'a, B, U, L, RS, WS> UartBridge<'a, B, U, L, RS, WS>
+where
+    B: UsbBus,
+    U: BridgeUart,
+    L: ControlLines,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    /// Creates a new bridge, and applies the current line coding and control line state of the
+    /// port to the UART and control lines.
+    pub fn new(port: SerialPort<'a, B, RS, WS>, mut uart: U, mut lines: L) -> Self {
+        let line_coding = *port.line_coding();
+        uart.set_line_coding(&line_coding);
+        lines.set_dtr(port.dtr());
+        lines.set_rts(port.rts());
+
+        UartBridge {
+            line_coding,
+            dtr: port.dtr(),
+            rts: port.rts(),
+            port,
+            uart,
+            lines,
+            state: SerialState::empty(),
+            events: SerialState::empty(),
+        }
+    }
+
+    /// Gets the serial port.
+    pub fn port(&self) -> &SerialPort<'a, B, RS, WS> {
+        &self.port
+    }
+
+    /// Mutably gets the serial port.
+    pub fn port_mut(&mut self) -> &mut SerialPort<'a, B, RS, WS> {
+        &mut self.port
+    }
+
+    /// Gets the UART.
+    pub fn uart(&self) -> &U {
+        &self.uart
+    }
+
+    /// Mutably gets the UART.
+    pub fn uart_mut(&mut self) -> &mut U {
+        &mut self.uart
+    }
+
+    /// Gets the control lines.
+    pub fn lines(&self) -> &L {
+        &self.lines
+    }
+
+    /// Mutably gets the control lines.
+    pub fn lines_mut(&mut self) -> &mut L {
+        &mut self.lines
+    }
+
+    /// Destroys the bridge and returns the serial port, UART and control lines.
+    pub fn release(self) -> (SerialPort<'a, B, RS, WS>, U, L) {
+        (self.port, self.uart, self.lines)
+    }
+
+    /// Moves data in both directions as far as the buffers and the UART allow, applies
+    /// configuration changes and sends a SERIAL_STATE notification if the state has changed.
+    ///
+    /// # Errors
+    ///
+    /// * [`Uart`](BridgeError::Uart) - Writing to the UART failed. The byte that failed to be
+    ///   written will be retried on the next call.
+    ///
+    /// Other errors from `usb-device` may also be propagated. `WouldBlock` is never returned.
+    pub fn poll(
+        &mut self,
+    ) -> core::result::Result<(), BridgeError<<U as serial::Write<u8>>::Error>> {
+        self.update_config();
+        self.usb_to_uart()?;
+        self.uart_to_usb()?;
+        self.update_serial_state()?;
+
+        Ok(())
+    }
+
+    fn update_config(&mut self) {
+        let line_coding = *self.port.line_coding();
+        if line_coding != self.line_coding {
+            self.uart.set_line_coding(&line_coding);
+            self.line_coding = line_coding;
+        }
+
+        if self.port.dtr() != self.dtr {
+            self.dtr = self.port.dtr();
+            self.lines.set_dtr(self.dtr);
+        }
+
+        if self.port.rts() != self.rts {
+            self.rts = self.port.rts();
+            self.lines.set_rts(self.rts);
+        }
+    }
+
+    fn usb_to_uart(
+        &mut self,
+    ) -> core::result::Result<(), BridgeError<<U as serial::Write<u8>>::Error>> {
+        let Self { port, uart, .. } = self;
+
+        port.poll()?;
+
+        let mut error = None;
+        port.read_buf
+            .read(usize::MAX, |data| {
+                for (i, &b) in data.iter().enumerate() {
+                    match uart.write(b) {
+                        Ok(()) => {}
+                        Err(nb::Error::WouldBlock) => return Ok(i),
+                        Err(nb::Error::Other(err)) => {
+                            error = Some(err);
+                            return Ok(i);
+                        }
+                    }
+                }
+
+                Ok::<usize, Infallible>(data.len())
+            })
+            .ok();
+
+        // Make room for the next packet right away so that the host doesn't have to wait for the
+        // next poll.
+        port.poll()?;
+
+        match error {
+            Some(err) => Err(BridgeError::Uart(err)),
+            None => Ok(()),
+        }
+    }
+
+    fn uart_to_usb(&mut self) -> Result<()> {
+        let Self {
+            port, uart, events, ..
+        } = self;
+
+        let written = port.write_with(port.writable_space(), |buf| {
+            let mut count = 0;
+
+            while count < buf.len() {
+                match uart.read() {
+                    Ok(b) => {
+                        buf[count] = b;
+                        count += 1;
+                    }
+                    Err(nb::Error::WouldBlock) => break,
+                    Err(nb::Error::Other(err)) => {
+                        *events |= uart.error_state(&err);
+                        break;
+                    }
+                }
+            }
+
+            count
+        });
+
+        match written {
+            // Data is left in the UART while writes are refused in suspend.
+            Ok(_) | Err(UsbError::WouldBlock) | Err(UsbError::InvalidState) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn update_serial_state(&mut self) -> Result<()> {
+        let lines = self.lines.input_state().difference(SerialState::EVENTS);
+
+        // Events are only reported once, so a state with events is always followed by another
+        // notification that clears them. New events wait until that notification has been sent,
+        // so that they are never mistaken for the events that were already reported.
+        let state = if self.state.intersection(SerialState::EVENTS).is_empty() {
+            lines | self.events.intersection(SerialState::EVENTS)
+        } else {
+            lines
+        };
+
+        if state == self.state {
+            return Ok(());
+        }
+
+        match self.port.send_serial_state(state) {
+            Ok(()) => {
+                if !state.intersection(SerialState::EVENTS).is_empty() {
+                    self.events = SerialState::empty();
+                }
+
+                self.state = state;
+                Ok(())
+            }
+            Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+forward_usb_class! {
+    impl<B, U, L, RS, WS> UsbClass<B> for UartBridge<'_, B, U, L, RS, WS>
+    where
+        B: UsbBus,
+        U: BridgeUart,
+        L: ControlLines,
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        self.port;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::cdc_acm::{ParityType, StopBits};
+    use crate::test_bus::{self, TestBus, comm_ep, read_ep, write_ep};
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct MockUart {
+        rx: VecDeque<nb::Result<u8, ()>>,
+        tx: Vec<u8>,
+        tx_space: usize,
+        line_coding: Option<LineCoding>,
+    }
+
+    impl serial::Read<u8> for MockUart {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.rx.pop_front().unwrap_or(Err(nb::Error::WouldBlock))
+        }
+    }
+
+    impl serial::Write<u8> for MockUart {
+        type Error = ();
+
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            if self.tx_space == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+
+            self.tx_space -= 1;
+            self.tx.push(word);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    impl BridgeUart for MockUart {
+        fn set_line_coding(&mut self, line_coding: &LineCoding) {
+            self.line_coding = Some(*line_coding);
+        }
+
+        fn error_state(&self, _error: &()) -> SerialState {
+            SerialState::PARITY_ERROR
+        }
+    }
+
+    #[derive(Default)]
+    struct MockLines {
+        dtr: bool,
+        rts: bool,
+        inputs: SerialState,
+    }
+
+    impl ControlLines for MockLines {
+        fn set_dtr(&mut self, active: bool) {
+            self.dtr = active;
+        }
+
+        fn set_rts(&mut self, active: bool) {
+            self.rts = active;
+        }
+
+        fn input_state(&mut self) -> SerialState {
+            self.inputs
+        }
+    }
+
+    #[test]
+    fn bridge() {
+        let (alloc, host) = TestBus::new();
+        let mut bridge = UartBridge::new(SerialPort::new(&alloc), MockUart::default(), ());
+        let mut dev = test_bus::device(&alloc);
+
+        assert_eq!(bridge.uart().line_coding, Some(LineCoding::default()));
+
+        // Host to UART, with backpressure from the UART
+        host.write(read_ep(), b"hello");
+        bridge.uart_mut().tx_space = 3;
+        bridge.poll().unwrap();
+        assert_eq!(bridge.uart().tx, b"hel");
+        bridge.uart_mut().tx_space = 10;
+        bridge.poll().unwrap();
+        assert_eq!(bridge.uart().tx, b"hello");
+
+        // UART to host, with a receive error
+        bridge
+            .uart_mut()
+            .rx
+            .extend([Ok(b'a'), Ok(b'b'), Err(nb::Error::Other(()))]);
+        bridge.poll().unwrap();
+        assert_eq!(host.read(write_ep()).unwrap(), b"ab");
+
+        let notification = host.read(comm_ep()).unwrap();
+        assert_eq!(notification[1], 0x20);
+        assert_eq!(&notification[8..], &[0x20, 0x00]);
+
+        // The error is cleared with another notification
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x00, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(host.read(comm_ep()), None);
+
+        // Line coding changes by the host are applied to the UART
+        let lc = LineCoding::new(115_200, 7, ParityType::Odd, StopBits::Two);
+        let setup = test_bus::setup(0x21, 0x20, 0, 0, 7);
+        assert!(host.control_out(&mut dev, &mut [&mut bridge], setup, &<[u8; 7]>::from(&lc)));
+        assert_eq!(bridge.uart().line_coding, Some(LineCoding::default()));
+        bridge.poll().unwrap();
+        assert_eq!(bridge.uart().line_coding, Some(lc));
+    }
+
+    #[test]
+    fn repeated_error_while_notification_pending() {
+        let (alloc, host) = TestBus::new();
+        let mut bridge = UartBridge::new(SerialPort::new(&alloc), MockUart::default(), ());
+        let _dev = test_bus::device(&alloc);
+
+        bridge.uart_mut().rx.push_back(Err(nb::Error::Other(())));
+        bridge.poll().unwrap();
+
+        // The same error occurs again while the first notification is still being sent
+        bridge.uart_mut().rx.push_back(Err(nb::Error::Other(())));
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x20, 0x00]);
+
+        // The first error is cleared before the second one is reported
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x00, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x20, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x00, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(host.read(comm_ep()), None);
+    }
+
+    #[test]
+    fn control_lines() {
+        let (alloc, host) = TestBus::new();
+        let mut bridge = UartBridge::new(
+            SerialPort::new(&alloc),
+            MockUart::default(),
+            MockLines::default(),
+        );
+        let mut dev = test_bus::device(&alloc);
+
+        // DTR and RTS set by the host are applied to the lines
+        let setup = test_bus::setup(0x21, 0x22, 0x0001, 0, 0);
+        assert!(host.control_out(&mut dev, &mut [&mut bridge], setup, &[]));
+        bridge.poll().unwrap();
+        assert!(bridge.lines().dtr);
+        assert!(!bridge.lines().rts);
+
+        let setup = test_bus::setup(0x21, 0x22, 0x0002, 0, 0);
+        assert!(host.control_out(&mut dev, &mut [&mut bridge], setup, &[]));
+        bridge.poll().unwrap();
+        assert!(!bridge.lines().dtr);
+        assert!(bridge.lines().rts);
+
+        // Input line changes are reported, and only once
+        bridge.lines_mut().inputs = SerialState::DCD | SerialState::DSR;
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x03, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(host.read(comm_ep()), None);
+
+        // Event flags from the lines are ignored, and errors keep the line state
+        bridge.lines_mut().inputs = SerialState::DSR | SerialState::BREAK;
+        bridge.uart_mut().rx.push_back(Err(nb::Error::Other(())));
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x22, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(&host.read(comm_ep()).unwrap()[8..], &[0x02, 0x00]);
+        bridge.poll().unwrap();
+        assert_eq!(host.read(comm_ep()), None);
+    }
+}
@@ -28,6 +28,24 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
         self.wpos = 0;
     }
 
+    // Total size of the buffer in bytes
+    pub fn capacity(&self) -> usize {
+        self.store.borrow().len()
+    }
+
+    // Data available for reading, without consuming it
+    pub fn data(&self) -> &[u8] {
+        &self.store.borrow()[self.rpos..self.wpos]
+    }
+
+    // Discards up to count bytes of data from the read end of the buffer and returns the number of
+    // bytes discarded
+    pub fn discard(&mut self, count: usize) -> usize {
+        let count = cmp::min(count, self.available_read());
+        self.rpos += count;
+        count
+    }
+
     // Amount of bytes available for reading
     pub fn available_read(&self) -> usize {
         self.wpos - self.rpos
@@ -82,10 +100,8 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
 
         assert!(self.available_write_without_discard() >= max_count);
 
-        f(&mut self.store.borrow_mut()[self.wpos..self.wpos + max_count]).map(|count| {
-            self.wpos += count;
-            count
-        })
+        f(&mut self.store.borrow_mut()[self.wpos..self.wpos + max_count])
+            .inspect(|count| self.wpos += count)
     }
 
     // Takes up to max_count bytes from the buffer and passes a slice pointing to them to a closure
@@ -99,10 +115,7 @@ impl<S: BorrowMut<[u8]>> Buffer<S> {
     ) -> Result<usize, E> {
         let count = cmp::min(max_count, self.available_read());
 
-        f(&self.store.borrow()[self.rpos..self.rpos + count]).map(|count| {
-            self.rpos += count;
-            count
-        })
+        f(&self.store.borrow()[self.rpos..self.rpos + count]).inspect(|count| self.rpos += count)
     }
 
     fn discard_already_read_data(&mut self) {
@@ -200,6 +213,18 @@ mod tests {
         assert_eq!(b.available_read(), 0);
     }
 
+    #[test]
+    fn discard_count() {
+        let mut b = Buf::new([0; LEN]);
+
+        assert_eq!(b.write(&DATA[0..4]), 4);
+        assert_eq!(b.discard(3), 3);
+        assert_eq!(b.data(), &DATA[3..4]);
+        assert_eq!(b.discard(3), 1);
+        assert_eq!(b.available_read(), 0);
+        assert_eq!(b.available_write(), LEN);
+    }
+
     #[test]
     fn discard() {
         let mut b = Buf::new([0; LEN]);
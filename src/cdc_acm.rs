@@ -1,7 +1,9 @@
+use crate::ms_os::{CONTROL_BUFFER_LEN, MS_OS_20_DESCRIPTOR_INDEX, MsOsDescriptors};
 use core::convert::TryInto;
 use core::mem;
 use usb_device::Result;
 use usb_device::class_prelude::*;
+use usb_device::descriptor::capability_type;
 use usb_device::descriptor::lang_id::LangID;
 use usb_device::device::DEFAULT_ALTERNATE_SETTING;
 
@@ -9,8 +11,8 @@ use usb_device::device::DEFAULT_ALTERNATE_SETTING;
 pub const USB_CLASS_CDC: u8 = 0x02;
 
 const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const USB_CLASS_VENDOR: u8 = 0xff;
 const CDC_SUBCLASS_ACM: u8 = 0x02;
-const CDC_PROTOCOL_NONE: u8 = 0x00;
 
 const CS_INTERFACE: u8 = 0x24;
 const CDC_TYPE_HEADER: u8 = 0x00;
@@ -25,6 +27,8 @@ const REQ_SET_LINE_CODING: u8 = 0x20;
 const REQ_GET_LINE_CODING: u8 = 0x21;
 const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
 
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
 /// Packet level implementation of a CDC-ACM serial port.
 ///
 /// This class can be used directly and it has the least overhead due to directly reading and
@@ -41,15 +45,24 @@ const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
 ///   terminated with a short packet, even if the bulk endpoint is used for stream-like data.
 pub struct CdcAcmClass<'a, B: UsbBus> {
     comm_if: InterfaceNumber,
-    comm_if_name: Option<(StringIndex, &'static str)>,
+    comm_if_string: Option<StringIndex>,
     comm_ep: EndpointIn<'a, B>,
     data_if: InterfaceNumber,
-    data_if_name: Option<(StringIndex, &'static str)>,
+    data_if_string: Option<StringIndex>,
+    function_string: Option<StringIndex>,
+    strings: PortStrings<'a>,
+    translations: &'a [PortStrings<'a>],
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
     line_coding: LineCoding,
+    default_line_coding: LineCoding,
+    line_coding_policy: Option<LineCodingPolicy>,
     dtr: bool,
     rts: bool,
+    ms_os_descriptors: Option<MsOsDescriptors<'a>>,
+    comm_subclass: u8,
+    comm_protocol: CommProtocol,
+    vendor_data_interface: bool,
 }
 
 impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
@@ -65,30 +78,74 @@ impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
     /// Creates a new CdcAcmClass with the provided UsbBus and max_packet_size in bytes. For
     /// full-speed devices, max_packet_size has to be one of 8, 16, 32 or 64. Additionally,
     /// this lets you specify optional names for the CDC interfaces, to better organize composite devices.
+    ///
+    /// The names only need to outlive the class, so they can be generated at runtime, for example
+    /// to include the serial number or a port index.
     pub fn new_with_interface_names<'alloc: 'a>(
         alloc: &'alloc UsbBusAllocator<B>,
         max_packet_size: u16,
-        comm_if_name: Option<&'static str>,
-        data_if_name: Option<&'static str>,
+        comm_if_name: Option<&'a str>,
+        data_if_name: Option<&'a str>,
     ) -> CdcAcmClass<'a, B> {
-        let comm_if_name = comm_if_name.map(|s| (alloc.string(), s));
-        let data_if_name = data_if_name.map(|s| (alloc.string(), s));
+        let strings = PortStrings {
+            comm_if: comm_if_name,
+            data_if: data_if_name,
+            ..PortStrings::default()
+        };
+
+        Self::new_with_port_strings(alloc, max_packet_size, strings, &[])
+    }
+
+    /// Creates a new CdcAcmClass with the provided UsbBus and max_packet_size in bytes. For
+    /// full-speed devices, max_packet_size has to be one of 8, 16, 32 or 64. Additionally, this
+    /// lets you specify the function name and the interface names in one or more languages.
+    ///
+    /// The first entry of `strings` is used for languages that have no entry of their own. The
+    /// languages should also be listed in the `StringDescriptors` of the `UsbDevice`, because hosts
+    /// only request strings in languages the device reports.
+    pub fn new_with_strings<'alloc: 'a>(
+        alloc: &'alloc UsbBusAllocator<B>,
+        max_packet_size: u16,
+        strings: &'a [PortStrings<'a>],
+    ) -> CdcAcmClass<'a, B> {
+        let default = strings.first().copied().unwrap_or_default();
+        Self::new_with_port_strings(alloc, max_packet_size, default, strings)
+    }
+
+    fn new_with_port_strings<'alloc: 'a>(
+        alloc: &'alloc UsbBusAllocator<B>,
+        max_packet_size: u16,
+        strings: PortStrings<'a>,
+        translations: &'a [PortStrings<'a>],
+    ) -> CdcAcmClass<'a, B> {
+        let has_string = |f: fn(&PortStrings<'a>) -> Option<&'a str>| {
+            f(&strings).is_some() || translations.iter().any(|t| f(t).is_some())
+        };
+
+        let function_string = has_string(|s| s.function).then(|| alloc.string());
+        let comm_if_string = has_string(|s| s.comm_if).then(|| alloc.string());
+        let data_if_string = has_string(|s| s.data_if).then(|| alloc.string());
+
         CdcAcmClass {
             comm_if: alloc.interface(),
-            comm_if_name,
-            comm_ep: alloc.interrupt(8, 255),
+            comm_if_string,
+            comm_ep: alloc.interrupt(16, 255),
             data_if: alloc.interface(),
-            data_if_name,
+            data_if_string,
+            function_string,
+            strings,
+            translations,
             read_ep: alloc.bulk(max_packet_size),
             write_ep: alloc.bulk(max_packet_size),
-            line_coding: LineCoding {
-                stop_bits: StopBits::One,
-                data_bits: 8,
-                parity_type: ParityType::None,
-                data_rate: 9_600,
-            },
+            line_coding: LineCoding::default(),
+            default_line_coding: LineCoding::default(),
+            line_coding_policy: None,
             dtr: false,
             rts: false,
+            ms_os_descriptors: None,
+            comm_subclass: CDC_SUBCLASS_ACM,
+            comm_protocol: CommProtocol::None,
+            vendor_data_interface: false,
         }
     }
 
@@ -104,6 +161,29 @@ impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
         &self.line_coding
     }
 
+    /// Gets the line coding that is used until the host sets one, and after a bus reset.
+    pub fn default_line_coding(&self) -> &LineCoding {
+        &self.default_line_coding
+    }
+
+    /// Sets the line coding that is used until the host sets one, and after a bus reset. This also
+    /// replaces the current line coding. The default is 9600 8N1.
+    pub fn set_default_line_coding(&mut self, line_coding: LineCoding) {
+        self.default_line_coding = line_coding;
+        self.line_coding = line_coding;
+    }
+
+    /// Sets a policy that decides whether line codings requested by the host are accepted,
+    /// replaced with the closest supported line coding, or rejected with a STALL. Line codings
+    /// that are not [supported](LineCoding::is_supported) are always rejected before the policy is
+    /// consulted. By default all supported line codings are accepted.
+    ///
+    /// If the policy replaces the line coding, the host sees the replacement when it reads the line
+    /// coding back with GET_LINE_CODING. A replacement that is not supported is rejected.
+    pub fn set_line_coding_policy(&mut self, policy: Option<LineCodingPolicy>) {
+        self.line_coding_policy = policy;
+    }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool {
         self.dtr
@@ -114,6 +194,97 @@ impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
         self.rts
     }
 
+    /// Gets the Microsoft OS 2.0 descriptors provided by this class, if any.
+    pub fn ms_os_descriptors(&self) -> Option<&MsOsDescriptors<'a>> {
+        self.ms_os_descriptors.as_ref()
+    }
+
+    /// Sets the Microsoft OS 2.0 descriptors provided by this class. This should be called before
+    /// the device is enumerated. See [`MsOsDescriptors`] for the device configuration required for
+    /// Windows to request them.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - The descriptor set doesn't fit
+    ///   into the control buffer of `usb-device`. The descriptors are not changed. See
+    ///   [`MsOsDescriptors`] for enabling the larger buffer.
+    pub fn set_ms_os_descriptors(
+        &mut self,
+        descriptors: Option<MsOsDescriptors<'a>>,
+    ) -> Result<()> {
+        if descriptors.is_some_and(|d| d.total_len() > CONTROL_BUFFER_LEN) {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        self.ms_os_descriptors = descriptors;
+        Ok(())
+    }
+
+    /// Gets the subclass code reported for the communication interface.
+    pub fn comm_subclass(&self) -> u8 {
+        self.comm_subclass
+    }
+
+    /// Sets the subclass code reported for the communication interface and the interface
+    /// association. The default is ACM (`0x02`). Requests are still handled as ACM requests, so
+    /// other values are only useful with vendor drivers. This should be called before the device
+    /// is enumerated.
+    pub fn set_comm_subclass(&mut self, subclass: u8) {
+        self.comm_subclass = subclass;
+    }
+
+    /// Gets the protocol reported for the communication interface.
+    pub fn comm_protocol(&self) -> CommProtocol {
+        self.comm_protocol
+    }
+
+    /// Sets the protocol reported for the communication interface and the interface association.
+    /// The default is [`CommProtocol::None`]. This only changes the descriptors and not how
+    /// requests are handled. This should be called before the device is enumerated.
+    pub fn set_comm_protocol(&mut self, protocol: CommProtocol) {
+        self.comm_protocol = protocol;
+    }
+
+    /// Returns `true` if the data interface reports the vendor-specific class.
+    pub fn vendor_data_interface(&self) -> bool {
+        self.vendor_data_interface
+    }
+
+    /// Sets whether the data interface reports the vendor-specific class (`0xff`) instead of CDC
+    /// Data (`0x0a`). This can be used to stop a host from binding its CDC driver to the data
+    /// interface. This should be called before the device is enumerated.
+    pub fn set_vendor_data_interface(&mut self, vendor: bool) {
+        self.vendor_data_interface = vendor;
+    }
+
+    /// Sends a SERIAL_STATE notification to the host through the interrupt endpoint. This reports
+    /// the state of the UART input lines and any errors that have occurred.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - The previous notification has not been
+    ///   sent yet.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn send_serial_state(&mut self, state: SerialState) -> Result<()> {
+        let state = state.bits().to_le_bytes();
+
+        self.comm_ep.write(&[
+            0xa1,                      // bmRequestType
+            NOTIFICATION_SERIAL_STATE, // bNotification
+            0x00,                      // wValue
+            0x00,
+            self.comm_if.into(), // wIndex
+            0x00,
+            0x02, // wLength
+            0x00,
+            state[0], // data
+            state[1],
+        ])?;
+
+        Ok(())
+    }
+
     /// Writes a single packet into the IN endpoint.
     pub fn write_packet(&mut self, data: &[u8]) -> Result<usize> {
         self.write_ep.write(data)
@@ -145,24 +316,24 @@ impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
     }
 }
 
-impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
+impl<'a, B: UsbBus> UsbClass<B> for CdcAcmClass<'a, B> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
         writer.iad(
             self.comm_if,
             2,
             USB_CLASS_CDC,
-            CDC_SUBCLASS_ACM,
-            CDC_PROTOCOL_NONE,
-            None,
+            self.comm_subclass,
+            self.comm_protocol.code(),
+            self.function_string,
         )?;
 
         writer.interface_alt(
             self.comm_if,
             DEFAULT_ALTERNATE_SETTING,
             USB_CLASS_CDC,
-            CDC_SUBCLASS_ACM,
-            CDC_PROTOCOL_NONE,
-            self.comm_if_name.map(|n| n.0),
+            self.comm_subclass,
+            self.comm_protocol.code(),
+            self.comm_if_string,
         )?;
 
         writer.write(
@@ -205,10 +376,14 @@ impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
         writer.interface_alt(
             self.data_if,
             DEFAULT_ALTERNATE_SETTING,
-            USB_CLASS_CDC_DATA,
+            if self.vendor_data_interface {
+                USB_CLASS_VENDOR
+            } else {
+                USB_CLASS_CDC_DATA
+            },
             0x00,
             0x00,
-            self.data_if_name.map(|n| n.0),
+            self.data_if_string,
         )?;
 
         writer.endpoint(&self.write_ep)?;
@@ -217,16 +392,36 @@ impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
         Ok(())
     }
 
-    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
-        match (self.comm_if_name, self.data_if_name) {
-            (Some((i, s)), _) if i == index => Some(s),
-            (_, Some((i, s))) if i == index => Some(s),
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        if let Some(descriptors) = &self.ms_os_descriptors {
+            writer.capability(
+                capability_type::PLATFORM,
+                &descriptors.platform_capability(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        let name = |f: fn(&PortStrings<'a>) -> Option<&'a str>| {
+            self.translations
+                .iter()
+                .filter(|s| s.lang_id == lang_id)
+                .find_map(f)
+                .or_else(|| f(&self.strings))
+        };
+
+        match Some(index) {
+            i if i == self.function_string => name(|s| s.function),
+            i if i == self.comm_if_string => name(|s| s.comm_if),
+            i if i == self.data_if_string => name(|s| s.data_if),
             _ => None,
         }
     }
 
     fn reset(&mut self) {
-        self.line_coding = LineCoding::default();
+        self.line_coding = self.default_line_coding;
         self.dtr = false;
         self.rts = false;
     }
@@ -234,6 +429,18 @@ impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
 
+        if let Some(descriptors) = &self.ms_os_descriptors
+            && req.request_type == control::RequestType::Vendor
+            && req.recipient == control::Recipient::Device
+            && req.request == descriptors.vendor_code()
+            && req.index == MS_OS_20_DESCRIPTOR_INDEX
+        {
+            let first_interface = self.comm_if.into();
+            xfer.accept(|data| descriptors.write_descriptor_set(first_interface, data))
+                .ok();
+            return;
+        }
+
         if !(req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
             && req.index == u8::from(self.comm_if) as u16)
@@ -244,15 +451,7 @@ impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
         match req.request {
             // REQ_GET_ENCAPSULATED_COMMAND is not really supported - it will be rejected below.
             REQ_GET_LINE_CODING if req.length == 7 => {
-                xfer.accept(|data| {
-                    data[0..4].copy_from_slice(&self.line_coding.data_rate.to_le_bytes());
-                    data[4] = self.line_coding.stop_bits as u8;
-                    data[5] = self.line_coding.parity_type as u8;
-                    data[6] = self.line_coding.data_bits;
-
-                    Ok(7)
-                })
-                .ok();
+                xfer.accept_with(&<[u8; 7]>::from(&self.line_coding)).ok();
             }
             _ => {
                 xfer.reject().ok();
@@ -277,13 +476,25 @@ impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
                 xfer.accept().ok();
             }
             REQ_SET_LINE_CODING if xfer.data().len() >= 7 => {
-                self.line_coding.data_rate =
-                    u32::from_le_bytes(xfer.data()[0..4].try_into().unwrap());
-                self.line_coding.stop_bits = xfer.data()[4].into();
-                self.line_coding.parity_type = xfer.data()[5].into();
-                self.line_coding.data_bits = xfer.data()[6];
+                let response = match LineCoding::try_from(xfer.data()) {
+                    Ok(line_coding) if line_coding.is_supported() => {
+                        match self.line_coding_policy {
+                            Some(policy) => policy(&line_coding),
+                            None => LineCodingResponse::Accept(line_coding),
+                        }
+                    }
+                    _ => LineCodingResponse::Reject,
+                };
 
-                xfer.accept().ok();
+                match response {
+                    LineCodingResponse::Accept(line_coding) if line_coding.is_supported() => {
+                        self.line_coding = line_coding;
+                        xfer.accept().ok();
+                    }
+                    _ => {
+                        xfer.reject().ok();
+                    }
+                }
             }
             REQ_SET_CONTROL_LINE_STATE => {
                 self.dtr = (req.value & 0x0001) != 0;
@@ -298,8 +509,189 @@ impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
     }
 }
 
+/// Names of the CDC-ACM function and interfaces in a specific language
+///
+/// The function name is written to the interface association descriptor, and hosts generally show
+/// it as the name of the whole port. Missing names fall back to the first entry passed to
+/// [`CdcAcmClass::new_with_strings`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PortStrings<'a> {
+    lang_id: LangID,
+    function: Option<&'a str>,
+    comm_if: Option<&'a str>,
+    data_if: Option<&'a str>,
+}
+
+impl Default for PortStrings<'_> {
+    fn default() -> Self {
+        Self::new(LangID::EN_US)
+    }
+}
+
+impl<'a> PortStrings<'a> {
+    /// Creates a new set of names in the provided language.
+    pub const fn new(lang_id: LangID) -> Self {
+        Self {
+            lang_id,
+            function: None,
+            comm_if: None,
+            data_if: None,
+        }
+    }
+
+    /// Specify the function name, e.g. "Debug Console" or "GPS".
+    pub const fn function(mut self, name: &'a str) -> Self {
+        self.function = Some(name);
+        self
+    }
+
+    /// Specify the name of the communication interface.
+    pub const fn comm_interface(mut self, name: &'a str) -> Self {
+        self.comm_if = Some(name);
+        self
+    }
+
+    /// Specify the name of the data interface.
+    pub const fn data_interface(mut self, name: &'a str) -> Self {
+        self.data_if = Some(name);
+        self
+    }
+}
+
+/// Decides how a line coding requested by the host is handled. See
+/// [`CdcAcmClass::set_line_coding_policy`].
+pub type LineCodingPolicy = fn(&LineCoding) -> LineCodingResponse;
+
+/// Response of a [`LineCodingPolicy`] to a requested line coding
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineCodingResponse {
+    /// Accept the request and use the contained line coding. This is either the requested line
+    /// coding, or the closest one the application supports.
+    Accept(LineCoding),
+
+    /// Reject the request with a STALL and keep the current line coding.
+    Reject,
+}
+
+/// UART state bitmap reported to the host with a SERIAL_STATE notification
+///
+/// The DCD, DSR and ring signal flags reflect the current state of the input lines, while the
+/// break, framing, parity and overrun flags report events that have occurred since the previous
+/// notification.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct SerialState(u16);
+
+impl SerialState {
+    /// Data carrier detect (bRxCarrier)
+    pub const DCD: SerialState = SerialState(1 << 0);
+
+    /// Data set ready (bTxCarrier)
+    pub const DSR: SerialState = SerialState(1 << 1);
+
+    /// Break condition detected (bBreak)
+    pub const BREAK: SerialState = SerialState(1 << 2);
+
+    /// Ring signal detected (bRingSignal)
+    pub const RING_SIGNAL: SerialState = SerialState(1 << 3);
+
+    /// Framing error (bFraming)
+    pub const FRAMING_ERROR: SerialState = SerialState(1 << 4);
+
+    /// Parity error (bParity)
+    pub const PARITY_ERROR: SerialState = SerialState(1 << 5);
+
+    /// Received data has been discarded due to an overrun (bOverRun)
+    pub const OVERRUN: SerialState = SerialState(1 << 6);
+
+    /// Flags that report events rather than the state of a line.
+    pub const EVENTS: SerialState =
+        SerialState(Self::BREAK.0 | Self::FRAMING_ERROR.0 | Self::PARITY_ERROR.0 | Self::OVERRUN.0);
+
+    /// Creates a state with no flags set.
+    pub const fn empty() -> Self {
+        SerialState(0)
+    }
+
+    /// Creates a state from the raw bitmap.
+    pub const fn from_bits(bits: u16) -> Self {
+        SerialState(bits)
+    }
+
+    /// Gets the raw bitmap.
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
+    /// Returns `true` if no flags are set.
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if all flags in `other` are set.
+    pub const fn contains(&self, other: SerialState) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the flags that are set in both `self` and `other`.
+    pub const fn intersection(&self, other: SerialState) -> Self {
+        SerialState(self.0 & other.0)
+    }
+
+    /// Returns the flags that are set in `self` but not in `other`.
+    pub const fn difference(&self, other: SerialState) -> Self {
+        SerialState(self.0 & !other.0)
+    }
+}
+
+impl core::ops::BitOr for SerialState {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        SerialState(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for SerialState {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Protocol code of the communication interface
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum CommProtocol {
+    /// No class specific protocol (`0x00`)
+    #[default]
+    None,
+
+    /// AT commands as defined by ITU-T V.250, formerly V.25ter (`0x01`)
+    V25ter,
+
+    /// AT commands as defined by PCCA-101 (`0x02`)
+    Pcca101,
+
+    /// Vendor-specific protocol (`0xff`)
+    Vendor,
+
+    /// Any other protocol code
+    Other(u8),
+}
+
+impl CommProtocol {
+    /// Gets the protocol code used in descriptors.
+    pub fn code(&self) -> u8 {
+        match self {
+            CommProtocol::None => 0x00,
+            CommProtocol::V25ter => 0x01,
+            CommProtocol::Pcca101 => 0x02,
+            CommProtocol::Vendor => 0xff,
+            CommProtocol::Other(code) => *code,
+        }
+    }
+}
+
 /// Number of stop bits for LineCoding
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum StopBits {
     /// 1 stop bit
     One = 0,
@@ -314,7 +706,7 @@ pub enum StopBits {
 impl From<u8> for StopBits {
     fn from(value: u8) -> Self {
         if value <= 2 {
-            unsafe { mem::transmute(value) }
+            unsafe { mem::transmute::<u8, StopBits>(value) }
         } else {
             StopBits::One
         }
@@ -322,7 +714,7 @@ impl From<u8> for StopBits {
 }
 
 /// Parity for LineCoding
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum ParityType {
     None = 0,
     Odd = 1,
@@ -334,17 +726,82 @@ pub enum ParityType {
 impl From<u8> for ParityType {
     fn from(value: u8) -> Self {
         if value <= 4 {
-            unsafe { mem::transmute(value) }
+            unsafe { mem::transmute::<u8, ParityType>(value) }
         } else {
             ParityType::None
         }
     }
 }
 
+/// Number of data bits for LineCoding, limited to the values allowed by the CDC PSTN specification
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataBits {
+    /// 5 data bits
+    Five = 5,
+
+    /// 6 data bits
+    Six = 6,
+
+    /// 7 data bits
+    Seven = 7,
+
+    /// 8 data bits
+    Eight = 8,
+
+    /// 16 data bits
+    Sixteen = 16,
+}
+
+impl TryFrom<u8> for DataBits {
+    type Error = UsbError;
+
+    /// Converts a number of data bits. Returns [`ParseError`](usb_device::UsbError::ParseError)
+    /// if it's not one of the values allowed by the CDC PSTN specification.
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            5 => Ok(DataBits::Five),
+            6 => Ok(DataBits::Six),
+            7 => Ok(DataBits::Seven),
+            8 => Ok(DataBits::Eight),
+            16 => Ok(DataBits::Sixteen),
+            _ => Err(UsbError::ParseError),
+        }
+    }
+}
+
+impl From<DataBits> for u8 {
+    fn from(data_bits: DataBits) -> Self {
+        data_bits as u8
+    }
+}
+
+impl StopBits {
+    /// Gets the number of stop bits in half bits, which is how UARTs that support 1.5 stop bits
+    /// usually count them.
+    pub fn half_bits(&self) -> u8 {
+        match self {
+            StopBits::One => 2,
+            StopBits::OnePointFive => 3,
+            StopBits::Two => 4,
+        }
+    }
+}
+
+impl ParityType {
+    /// Returns `true` if a parity bit is sent with each character.
+    pub fn has_parity_bit(&self) -> bool {
+        *self != ParityType::None
+    }
+}
+
 /// Line coding parameters
 ///
 /// This is provided by the host for specifying the standard UART parameters such as baud rate. Can
 /// be ignored if you don't plan to interface with a physical UART.
+///
+/// The line coding converts to and from the 7 byte wire format used by the SET_LINE_CODING and
+/// GET_LINE_CODING requests.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct LineCoding {
     stop_bits: StopBits,
     data_bits: u8,
@@ -353,6 +810,29 @@ pub struct LineCoding {
 }
 
 impl LineCoding {
+    /// Lowest data rate accepted by [`is_supported`](LineCoding::is_supported), in bits per second
+    pub const MIN_DATA_RATE: u32 = 50;
+
+    /// Highest data rate accepted by [`is_supported`](LineCoding::is_supported), in bits per
+    /// second. This is the bit rate of a full-speed USB bus.
+    pub const MAX_DATA_RATE: u32 = 12_000_000;
+
+    /// Creates a new line coding with the given data rate in bits per second, number of data bits,
+    /// parity and number of stop bits.
+    pub const fn new(
+        data_rate: u32,
+        data_bits: u8,
+        parity_type: ParityType,
+        stop_bits: StopBits,
+    ) -> Self {
+        LineCoding {
+            stop_bits,
+            data_bits,
+            parity_type,
+            data_rate,
+        }
+    }
+
     /// Gets the number of stop bits for UART communication.
     pub fn stop_bits(&self) -> StopBits {
         self.stop_bits
@@ -363,6 +843,19 @@ impl LineCoding {
         self.data_bits
     }
 
+    /// Gets the number of data bits for UART communication as a [`DataBits`], or `None` if it's
+    /// not one of the values allowed by the CDC PSTN specification.
+    pub fn data_bits_type(&self) -> Option<DataBits> {
+        DataBits::try_from(self.data_bits).ok()
+    }
+
+    /// Gets the number of bits in a character including the parity bit, but not the start and stop
+    /// bits. UARTs that don't configure parity separately from the data bits, such as those of
+    /// STM32 microcontrollers, take this as the word length.
+    pub fn word_length(&self) -> u8 {
+        self.data_bits + self.parity_type.has_parity_bit() as u8
+    }
+
     /// Gets the parity type for UART communication.
     pub fn parity_type(&self) -> ParityType {
         self.parity_type
@@ -372,15 +865,326 @@ impl LineCoding {
     pub fn data_rate(&self) -> u32 {
         self.data_rate
     }
+
+    /// Returns `true` if the line coding can be implemented by a UART. The CDC PSTN specification
+    /// allows 5, 6, 7, 8 or 16 data bits, the data rate must be between
+    /// [`MIN_DATA_RATE`](LineCoding::MIN_DATA_RATE) and
+    /// [`MAX_DATA_RATE`](LineCoding::MAX_DATA_RATE), and 1.5 stop bits are only used with 5 data
+    /// bits like on 16550 compatible UARTs. Line codings that are not supported are rejected with a
+    /// STALL when the host tries to set them.
+    pub fn is_supported(&self) -> bool {
+        matches!(self.data_bits, 5 | 6 | 7 | 8 | 16)
+            && (Self::MIN_DATA_RATE..=Self::MAX_DATA_RATE).contains(&self.data_rate)
+            && (self.stop_bits != StopBits::OnePointFive || self.data_bits == 5)
+    }
 }
 
 impl Default for LineCoding {
     fn default() -> Self {
-        LineCoding {
-            stop_bits: StopBits::One,
-            data_bits: 8,
-            parity_type: ParityType::None,
-            data_rate: 9_600,
+        LineCoding::new(9_600, 8, ParityType::None, StopBits::One)
+    }
+}
+
+impl From<&LineCoding> for [u8; 7] {
+    fn from(line_coding: &LineCoding) -> Self {
+        let mut data = [0u8; 7];
+
+        data[0..4].copy_from_slice(&line_coding.data_rate.to_le_bytes());
+        data[4] = line_coding.stop_bits as u8;
+        data[5] = line_coding.parity_type as u8;
+        data[6] = line_coding.data_bits;
+
+        data
+    }
+}
+
+impl TryFrom<&[u8]> for LineCoding {
+    type Error = UsbError;
+
+    /// Parses a line coding from the wire format. Returns
+    /// [`ParseError`](usb_device::UsbError::ParseError) if `data` is shorter than 7 bytes, or the
+    /// stop bits or parity type are out of range.
+    fn try_from(data: &[u8]) -> Result<Self> {
+        if data.len() < 7 || data[4] > StopBits::Two as u8 || data[5] > ParityType::Space as u8 {
+            return Err(UsbError::ParseError);
         }
+
+        Ok(LineCoding {
+            data_rate: u32::from_le_bytes(data[0..4].try_into().unwrap()),
+            stop_bits: data[4].into(),
+            parity_type: data[5].into(),
+            data_bits: data[6],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_bus::{self, TestBus, TestHost};
+    use std::string::String;
+    use std::vec::Vec;
+    use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+
+    /// Gets a string descriptor as the host would.
+    fn get_string(
+        host: &TestHost,
+        dev: &mut UsbDevice<'_, TestBus>,
+        class: &mut CdcAcmClass<'_, TestBus>,
+        index: u8,
+        lang_id: LangID,
+    ) -> Option<String> {
+        let value = 0x0300 | index as u16;
+        let setup = test_bus::setup(0x80, 0x06, value, lang_id.into(), 255);
+        let data = host.control_in(dev, &mut [class], setup)?;
+        let units = data[2..]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+
+        Some(String::from_utf16(&units).unwrap())
+    }
+
+    #[test]
+    fn strings() {
+        // Names only need to outlive the class.
+        let index = 3;
+        let function = std::format!("Port {index}");
+        let strings = [
+            PortStrings::new(LangID::EN_US)
+                .function(&function)
+                .comm_interface("Control"),
+            PortStrings::new(LangID::DE_DE).function("Anschluss"),
+        ];
+
+        let (alloc, host) = TestBus::new();
+        let mut class = CdcAcmClass::new_with_strings(&alloc, 64, &strings);
+        let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x16c0, 0x27dd))
+            .composite_with_iads()
+            .build();
+
+        // The function string is referenced by the interface association descriptor.
+        let setup = test_bus::setup(0x80, 0x06, 0x0200, 0, 255);
+        let config = host.control_in(&mut dev, &mut [&mut class], setup).unwrap();
+        let iad = &config[9..17];
+        assert_eq!(iad[..2], [8, 0x0b]);
+        let function_index = iad[7];
+        assert_ne!(function_index, 0);
+
+        let mut get = |index, lang_id| get_string(&host, &mut dev, &mut class, index, lang_id);
+        assert_eq!(get(function_index, LangID::EN_US).unwrap(), "Port 3");
+        assert_eq!(get(function_index, LangID::DE_DE).unwrap(), "Anschluss");
+
+        // Missing names fall back to the first entry, for other languages too.
+        assert_eq!(get(function_index + 1, LangID::DE_DE).unwrap(), "Control");
+        assert_eq!(get(function_index, LangID::FR_FR).unwrap(), "Port 3");
+
+        // The data interface has no name, so no string is allocated for it.
+        assert_eq!(get(function_index + 2, LangID::EN_US), None);
+    }
+
+    #[test]
+    fn line_coding_bytes() {
+        let lc = LineCoding::new(115_200, 7, ParityType::Even, StopBits::Two);
+        let data = <[u8; 7]>::from(&lc);
+
+        assert_eq!(data, [0x00, 0xc2, 0x01, 0x00, 0x02, 0x02, 0x07]);
+        assert_eq!(LineCoding::try_from(&data[..]), Ok(lc));
+        assert_eq!(LineCoding::try_from(&data[..6]), Err(UsbError::ParseError));
+        assert_eq!(
+            LineCoding::try_from(&[0x80, 0x25, 0x00, 0x00, 0x00, 0x05, 0x08][..]),
+            Err(UsbError::ParseError)
+        );
+    }
+
+    #[test]
+    fn line_coding_supported() {
+        assert!(LineCoding::default().is_supported());
+        assert!(LineCoding::new(300, 16, ParityType::None, StopBits::One).is_supported());
+        assert!(!LineCoding::new(9_600, 0, ParityType::None, StopBits::One).is_supported());
+        assert!(!LineCoding::new(9_600, 9, ParityType::None, StopBits::One).is_supported());
+        assert!(!LineCoding::new(0, 8, ParityType::None, StopBits::One).is_supported());
+        assert!(!LineCoding::new(49, 8, ParityType::None, StopBits::One).is_supported());
+        assert!(LineCoding::new(12_000_000, 8, ParityType::None, StopBits::One).is_supported());
+        assert!(!LineCoding::new(12_000_001, 8, ParityType::None, StopBits::One).is_supported());
+        assert!(LineCoding::new(9_600, 5, ParityType::Odd, StopBits::OnePointFive).is_supported());
+        assert!(!LineCoding::new(9_600, 8, ParityType::Odd, StopBits::OnePointFive).is_supported());
+    }
+
+    #[test]
+    fn line_coding_conversions() {
+        let lc = LineCoding::new(115_200, 7, ParityType::Even, StopBits::OnePointFive);
+        assert_eq!(lc.data_bits_type(), Some(DataBits::Seven));
+        assert_eq!(lc.word_length(), 8);
+        assert_eq!(lc.stop_bits().half_bits(), 3);
+        assert!(lc.parity_type().has_parity_bit());
+
+        let lc = LineCoding::default();
+        assert_eq!(lc.data_bits_type(), Some(DataBits::Eight));
+        assert_eq!(lc.word_length(), 8);
+        assert_eq!(lc.stop_bits().half_bits(), 2);
+        assert!(!lc.parity_type().has_parity_bit());
+
+        let lc = LineCoding::new(9_600, 9, ParityType::None, StopBits::Two);
+        assert_eq!(lc.data_bits_type(), None);
+        assert_eq!(lc.stop_bits().half_bits(), 4);
+
+        for bits in [5, 6, 7, 8, 16] {
+            assert_eq!(u8::from(DataBits::try_from(bits).unwrap()), bits);
+        }
+        assert_eq!(DataBits::try_from(0), Err(UsbError::ParseError));
+    }
+
+    #[test]
+    fn ms_os_descriptor_set_size() {
+        use crate::ms_os::RegistryProperty;
+        use crate::test_bus::TestBus;
+
+        const GUIDS: &[RegistryProperty] = &[RegistryProperty::device_interface_guids(&[
+            "{8FE6D4D7-49DD-41E7-9486-49AFC6BFE475}",
+        ])];
+
+        let (alloc, _host) = TestBus::new();
+        let mut class = CdcAcmClass::new(&alloc, 64);
+
+        assert_eq!(
+            class.set_ms_os_descriptors(Some(MsOsDescriptors::winusb(0x01, &[]))),
+            Ok(())
+        );
+
+        let result = class.set_ms_os_descriptors(Some(MsOsDescriptors::winusb(0x01, GUIDS)));
+        if cfg!(feature = "control-buffer-256") {
+            assert_eq!(result, Ok(()));
+            assert_eq!(class.ms_os_descriptors().unwrap().total_len(), 178);
+        } else {
+            assert_eq!(result, Err(UsbError::BufferOverflow));
+            assert_eq!(class.ms_os_descriptors().unwrap().total_len(), 46);
+        }
+    }
+
+    #[test]
+    fn line_coding_requests() {
+        let (alloc, host) = TestBus::new();
+        let mut class = CdcAcmClass::new(&alloc, 64);
+        let mut dev = test_bus::device(&alloc);
+
+        class.set_line_coding_policy(Some(|requested| {
+            match (requested.parity_type(), requested.data_rate()) {
+                (ParityType::Odd, _) => LineCodingResponse::Reject,
+                // Replaced with an unsupported line coding, which must be rejected too
+                (_, 1_200) => LineCodingResponse::Accept(LineCoding::new(
+                    0,
+                    8,
+                    ParityType::None,
+                    StopBits::One,
+                )),
+                (_, rate) if rate > 115_200 => LineCodingResponse::Accept(LineCoding::new(
+                    115_200,
+                    requested.data_bits(),
+                    requested.parity_type(),
+                    requested.stop_bits(),
+                )),
+                _ => LineCodingResponse::Accept(*requested),
+            }
+        }));
+
+        let mut set = |line_coding: LineCoding| {
+            let setup = test_bus::setup(0x21, REQ_SET_LINE_CODING, 0, 0, 7);
+            let data = <[u8; 7]>::from(&line_coding);
+            host.control_out(&mut dev, &mut [&mut class], setup, &data)
+        };
+
+        assert!(set(LineCoding::new(
+            57_600,
+            7,
+            ParityType::Even,
+            StopBits::Two
+        )));
+        assert!(set(LineCoding::new(
+            921_600,
+            8,
+            ParityType::None,
+            StopBits::One
+        )));
+        assert!(!set(LineCoding::new(
+            9_600,
+            8,
+            ParityType::Odd,
+            StopBits::One
+        )));
+        assert!(!set(LineCoding::new(
+            1_200,
+            8,
+            ParityType::None,
+            StopBits::One
+        )));
+        assert!(!set(LineCoding::new(
+            9_600,
+            9,
+            ParityType::None,
+            StopBits::One
+        )));
+
+        // Only the clamped line coding was stored, and the host reads it back.
+        let clamped = LineCoding::new(115_200, 8, ParityType::None, StopBits::One);
+        assert_eq!(class.line_coding(), &clamped);
+
+        let setup = test_bus::setup(0xa1, REQ_GET_LINE_CODING, 0, 0, 7);
+        let data = host.control_in(&mut dev, &mut [&mut class], setup).unwrap();
+        assert_eq!(LineCoding::try_from(&data[..]), Ok(clamped));
+    }
+
+    #[test]
+    fn ms_os_descriptor_requests() {
+        let (alloc, host) = TestBus::new();
+        let mut class = CdcAcmClass::new(&alloc, 64);
+        let mut dev = test_bus::device(&alloc);
+
+        let descriptors = MsOsDescriptors::winusb(0x01, &[]);
+        class.set_ms_os_descriptors(Some(descriptors)).unwrap();
+
+        // The host finds the vendor code and the descriptor set length in the platform capability
+        // of the BOS descriptor.
+        let setup = test_bus::setup(0x80, 0x06, 0x0f00, 0, 255);
+        let bos = host.control_in(&mut dev, &mut [&mut class], setup).unwrap();
+        assert_eq!(bos[1], 0x0f);
+        assert_eq!(u16::from_le_bytes([bos[2], bos[3]]) as usize, bos.len());
+
+        let mut capabilities = &bos[5..];
+        let platform = loop {
+            let (capability, rest) = capabilities.split_at(capabilities[0] as usize);
+            if capability[2] == capability_type::PLATFORM {
+                break capability;
+            }
+
+            capabilities = rest;
+        };
+        assert_eq!(platform.len(), 28);
+        let total_len = u16::from_le_bytes([platform[24], platform[25]]);
+        let vendor_code = platform[26];
+        assert_eq!(total_len as usize, descriptors.total_len());
+        assert_eq!(vendor_code, 0x01);
+
+        // The descriptor set is returned by the vendor request, for the interfaces of the class.
+        let setup = test_bus::setup(0xc0, vendor_code, 0, MS_OS_20_DESCRIPTOR_INDEX, total_len);
+        let set = host.control_in(&mut dev, &mut [&mut class], setup).unwrap();
+
+        let mut expected = [0u8; CONTROL_BUFFER_LEN];
+        let len = descriptors
+            .write_descriptor_set(class.comm_if.into(), &mut expected)
+            .unwrap();
+        assert_eq!(set, &expected[..len]);
+
+        // Other vendor requests are not answered.
+        let setup = test_bus::setup(
+            0xc0,
+            vendor_code + 1,
+            0,
+            MS_OS_20_DESCRIPTOR_INDEX,
+            total_len,
+        );
+        assert_eq!(host.control_in(&mut dev, &mut [&mut class], setup), None);
     }
 }
@@ -0,0 +1,19 @@
+/// Monotonic millisecond clock supplied by the application
+///
+/// Used for timeouts. The counter may wrap around, so only differences between readings are
+/// meaningful. Any `FnMut() -> u32` closure can be used as a clock.
+pub trait Clock {
+    /// Gets the current time in milliseconds.
+    fn now_ms(&mut self) -> u32;
+}
+
+impl<F: FnMut() -> u32> Clock for F {
+    fn now_ms(&mut self) -> u32 {
+        self()
+    }
+}
+
+/// Gets the number of milliseconds elapsed since `since`, taking wrap-around into account.
+pub(crate) fn elapsed_ms(clock: &mut impl Clock, since: u32) -> u32 {
+    clock.now_ms().wrapping_sub(since)
+}
@@ -0,0 +1,629 @@
+use crate::buffer::DefaultBufferStore;
+use crate::serial_port::SerialPort;
+use crate::wrapper::forward_usb_class;
+use core::borrow::BorrowMut;
+use usb_device::Result;
+use usb_device::class_prelude::*;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+const BELL: &[u8] = b"\x07";
+const ERASE_CHAR: &[u8] = b"\x08 \x08";
+const INTERRUPT: &[u8] = b"^C\r\n";
+const NEWLINE: &[u8] = b"\r\n";
+
+/// Input processing mode of a [`Console`]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum ConsoleMode {
+    /// Input is assembled into lines with echo and line editing, and read with
+    /// [`read_line`](Console::read_line). `\n` is written as `\r\n`.
+    #[default]
+    Cooked,
+
+    /// Input and output are passed through unmodified, and input is read with
+    /// [`read`](Console::read).
+    Raw,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum InputState {
+    Normal,
+    AfterCr,
+    Escape,
+    Csi,
+}
+
+/// Interactive console line discipline over a [`SerialPort`].
+///
+/// In cooked mode, received bytes are assembled into a line in the caller-provided line buffer.
+/// The following input is handled:
+///
+/// * CR, LF or CR LF ends the line.
+/// * Backspace or DEL erases the last character.
+/// * Ctrl-U erases the whole line.
+/// * Ctrl-C discards the line and sets the interrupt flag, which can be read with
+///   [`take_interrupt`](Console::take_interrupt).
+/// * Up and down arrows recall previous lines from the history.
+/// * Other control characters and escape sequences are ignored.
+///
+/// If echo is enabled, edits are echoed back to the terminal. Input is only consumed from the read
+/// buffer when there's room for its echo in the write buffer, and no input is consumed after a
+/// complete line until it has been read, so pasted input is not lost. The serial port stops
+/// accepting packets from the host while its read buffer is full.
+///
+/// Completed lines are stored in the caller-provided history buffer, newest first, until the
+/// buffer is full, after which the oldest lines are dropped. An empty history buffer disables the
+/// history.
+///
+/// Pass the console to `UsbDevice::poll`, not its serial port, so that a bus reset also discards
+/// the line being edited.
+pub struct Console<'a, B, L, H, RS = DefaultBufferStore, WS = DefaultBufferStore>
+where
+    B: UsbBus,
+    L: BorrowMut<[u8]>,
+    H: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    port: SerialPort<'a, B, RS, WS>,
+    mode: ConsoleMode,
+    echo: bool,
+    input: InputState,
+    interrupted: bool,
+    line: L,
+    line_len: usize,
+    line_ready: bool,
+    history: H,
+    history_len: usize,
+    recall: Option<usize>,
+}
+
+impl<'a, B, L, H, RS, WS> Console<'a, B, L, H, RS, WS>
+where
+    B: UsbBus,
+    L: BorrowMut<[u8]>,
+    H: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    /// Creates a new console in cooked mode with echo enabled. The length of `line` limits the
+    /// length of input lines.
+    pub fn new(port: SerialPort<'a, B, RS, WS>, line: L, history: H) -> Self {
+        Console {
+            port,
+            mode: ConsoleMode::Cooked,
+            echo: true,
+            input: InputState::Normal,
+            interrupted: false,
+            line,
+            line_len: 0,
+            line_ready: false,
+            history,
+            history_len: 0,
+            recall: None,
+        }
+    }
+
+    /// Gets the serial port.
+    pub fn port(&self) -> &SerialPort<'a, B, RS, WS> {
+        &self.port
+    }
+
+    /// Mutably gets the serial port. Reading from the port directly in cooked mode bypasses the
+    /// line discipline.
+    pub fn port_mut(&mut self) -> &mut SerialPort<'a, B, RS, WS> {
+        &mut self.port
+    }
+
+    /// Destroys the console and returns the serial port and the line and history buffers.
+    pub fn release(self) -> (SerialPort<'a, B, RS, WS>, L, H) {
+        (self.port, self.line, self.history)
+    }
+
+    /// Gets the input processing mode.
+    pub fn mode(&self) -> ConsoleMode {
+        self.mode
+    }
+
+    /// Sets the input processing mode. Any partially entered line is discarded.
+    pub fn set_mode(&mut self, mode: ConsoleMode) {
+        self.mode = mode;
+        self.input = InputState::Normal;
+        self.line_len = 0;
+        self.line_ready = false;
+        self.recall = None;
+    }
+
+    /// Returns `true` if input is echoed in cooked mode.
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// Enables or disables echo in cooked mode.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Returns `true` if Ctrl-C has been received since the last call, and clears the flag. In raw
+    /// mode the flag is set in addition to returning the byte from [`read`](Console::read).
+    pub fn take_interrupt(&mut self) -> bool {
+        core::mem::take(&mut self.interrupted)
+    }
+
+    /// Reads a line in cooked mode and returns it without the line terminator. The line is
+    /// borrowed from the line buffer until the next call.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No complete line has been received
+    ///   yet.
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The console is in raw mode.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn read_line(&mut self) -> Result<&[u8]> {
+        if self.mode != ConsoleMode::Cooked {
+            return Err(UsbError::InvalidState);
+        }
+
+        if self.line_ready {
+            self.line_ready = false;
+            self.line_len = 0;
+        }
+
+        while !self.line_ready {
+            if self.port.read_buf.available_read() == 0 {
+                self.port.poll()?;
+            }
+
+            let Some(&b) = self.port.read_buf.data().first() else {
+                break;
+            };
+
+            if !self.input_byte(b) {
+                // No room for the echo, so leave the byte for later.
+                break;
+            }
+
+            self.port.read_buf.discard(1);
+        }
+
+        match self.port.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => {}
+            Err(err) => return Err(err),
+        }
+
+        if self.line_ready {
+            Ok(&self.line.borrow()[..self.line_len])
+        } else {
+            Err(UsbError::WouldBlock)
+        }
+    }
+
+    /// Reads bytes in raw mode into `data` and returns the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes available for reading.
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The console is in cooked mode.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        if self.mode != ConsoleMode::Raw {
+            return Err(UsbError::InvalidState);
+        }
+
+        let count = self.port.read(data)?;
+
+        if data[..count].contains(&CTRL_C) {
+            self.interrupted = true;
+        }
+
+        Ok(count)
+    }
+
+    /// Writes bytes to the port and returns the number of bytes of `data` written. In cooked mode
+    /// `\n` is written as `\r\n`.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes could be written because the
+    ///   buffers are full.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if self.mode == ConsoleMode::Raw {
+            return self.port.write(data);
+        }
+
+        let mut count = 0;
+
+        for chunk in data.split_inclusive(|&b| b == b'\n') {
+            let (text, newline) = match chunk.strip_suffix(b"\n") {
+                Some(text) => (text, true),
+                None => (chunk, false),
+            };
+
+            if !text.is_empty() {
+                let written = match self.port.write(text) {
+                    Ok(written) => written,
+                    Err(UsbError::WouldBlock) => break,
+                    Err(err) => return Err(err),
+                };
+
+                count += written;
+
+                if written < text.len() {
+                    break;
+                }
+            }
+
+            if newline {
+                // The newline is written as a unit so that CR LF is never split.
+                let written = self.port.write_with(NEWLINE.len(), |buf| {
+                    buf.copy_from_slice(NEWLINE);
+                    NEWLINE.len()
+                });
+
+                match written {
+                    Ok(_) => count += 1,
+                    Err(UsbError::WouldBlock) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        if count == 0 && !data.is_empty() {
+            Err(UsbError::WouldBlock)
+        } else {
+            Ok(count)
+        }
+    }
+
+    /// Processes one byte of input in cooked mode. Returns `false` without changing any state if
+    /// there's no room for the echo.
+    fn input_byte(&mut self, b: u8) -> bool {
+        match (self.input, b) {
+            (InputState::AfterCr, b'\n') => {
+                self.input = InputState::Normal;
+                true
+            }
+            (InputState::Escape, b'[') => {
+                self.input = InputState::Csi;
+                true
+            }
+            (InputState::Escape, _) => {
+                self.input = InputState::Normal;
+                true
+            }
+            (InputState::Csi, b'A' | b'B') => {
+                if self.recall_history(b == b'A') {
+                    self.input = InputState::Normal;
+                    true
+                } else {
+                    false
+                }
+            }
+            (InputState::Csi, 0x40..=0x7e) => {
+                self.input = InputState::Normal;
+                true
+            }
+            (InputState::Csi, _) => true,
+            (_, b'\r' | b'\n') => {
+                if !self.echo_all(&[NEWLINE]) {
+                    return false;
+                }
+
+                self.add_history();
+                self.recall = None;
+                self.line_ready = true;
+                self.input = if b == b'\r' {
+                    InputState::AfterCr
+                } else {
+                    InputState::Normal
+                };
+                true
+            }
+            (_, CTRL_C) => {
+                if !self.echo_all(&[INTERRUPT]) {
+                    return false;
+                }
+
+                self.line_len = 0;
+                self.recall = None;
+                self.interrupted = true;
+                self.input = InputState::Normal;
+                true
+            }
+            (_, BACKSPACE | DEL) => {
+                if self.line_len > 0 {
+                    if !self.echo_all(&[ERASE_CHAR]) {
+                        return false;
+                    }
+
+                    self.line_len = char_start(&self.line.borrow()[..self.line_len]);
+                }
+
+                self.input = InputState::Normal;
+                true
+            }
+            (_, CTRL_U) => {
+                if !self.replace_line(None) {
+                    return false;
+                }
+
+                self.input = InputState::Normal;
+                true
+            }
+            (_, ESC) => {
+                self.input = InputState::Escape;
+                true
+            }
+            (_, 0x00..=0x1f) => {
+                self.input = InputState::Normal;
+                true
+            }
+            (_, b) => {
+                let line = self.line.borrow_mut();
+
+                if self.line_len < line.len() {
+                    if !echo_all(self.echo, &mut self.port, &[&[b]]) {
+                        return false;
+                    }
+
+                    line[self.line_len] = b;
+                    self.line_len += 1;
+                } else if !echo_all(self.echo, &mut self.port, &[BELL]) {
+                    return false;
+                }
+
+                self.input = InputState::Normal;
+                true
+            }
+        }
+    }
+
+    fn echo_all(&mut self, parts: &[&[u8]]) -> bool {
+        echo_all(self.echo, &mut self.port, parts)
+    }
+
+    /// Recalls an older or newer line from the history. Returns `false` if there's no room for
+    /// the echo.
+    fn recall_history(&mut self, older: bool) -> bool {
+        let count = self.history.borrow()[..self.history_len]
+            .iter()
+            .filter(|&&b| b == 0)
+            .count();
+
+        let recall = match (self.recall, older) {
+            (None, true) if count > 0 => Some(0),
+            (Some(index), true) if index + 1 < count => Some(index + 1),
+            (Some(index), false) => index.checked_sub(1),
+            _ => return true,
+        };
+
+        if !self.replace_line(recall) {
+            return false;
+        }
+
+        self.recall = recall;
+        true
+    }
+
+    /// Replaces the current line with a line from the history, or erases it if `entry` is `None`.
+    /// Returns `false` if there's no room for the echo.
+    fn replace_line(&mut self, entry: Option<usize>) -> bool {
+        let history = &self.history.borrow()[..self.history_len];
+        let text = entry.map_or(&[][..], |index| {
+            history.split(|&b| b == 0).nth(index).unwrap()
+        });
+        let line = self.line.borrow_mut();
+        let text = &text[..text.len().min(line.len())];
+
+        // Move the cursor back over the current line and erase to the end of the terminal line.
+        let chars = line[..self.line_len]
+            .iter()
+            .filter(|&&b| !is_continuation(b))
+            .count();
+        let mut erase = [0u8; 24];
+        let erase = match chars {
+            0 => &[][..],
+            _ => csi(&mut erase, chars, b'D'),
+        };
+
+        if !echo_all(self.echo, &mut self.port, &[erase, b"\x1b[K", text]) {
+            // Give up on echoing if it can never fit into the write buffer.
+            if erase.len() + 3 + text.len() <= self.port.write_buf.capacity() {
+                return false;
+            }
+        }
+
+        line[..text.len()].copy_from_slice(text);
+        self.line_len = text.len();
+        true
+    }
+
+    /// Adds the current line to the history unless it's empty or the same as the newest entry.
+    fn add_history(&mut self) {
+        let line = &self.line.borrow()[..self.line_len];
+        let history = self.history.borrow_mut();
+        let entry_len = line.len() + 1;
+
+        let newest = history[..self.history_len].split(|&b| b == 0).next();
+
+        if line.is_empty()
+            || entry_len > history.len()
+            || (self.history_len > 0 && newest == Some(line))
+        {
+            return;
+        }
+
+        let kept = self.history_len.min(history.len() - entry_len);
+        history.copy_within(..kept, entry_len);
+
+        // Drop any partial entry at the end.
+        self.history_len = history[entry_len..entry_len + kept]
+            .iter()
+            .rposition(|&b| b == 0)
+            .map_or(entry_len, |i| entry_len + i + 1);
+
+        history[..line.len()].copy_from_slice(line);
+        history[line.len()] = 0;
+    }
+}
+
+/// Writes all parts to the write buffer as one unit if `echo` is enabled. Returns `false` if
+/// there's not enough room.
+fn echo_all<B, RS, WS>(echo: bool, port: &mut SerialPort<'_, B, RS, WS>, parts: &[&[u8]]) -> bool
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    !echo || port.write_parts(parts).is_ok()
+}
+
+/// Formats a CSI sequence with one numeric parameter into `buf`.
+fn csi(buf: &mut [u8; 24], n: usize, command: u8) -> &[u8] {
+    let mut digits = [0u8; 20];
+    let mut count = 0;
+    let mut n = n;
+
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        count += 1;
+        n /= 10;
+
+        if n == 0 {
+            break;
+        }
+    }
+
+    buf[0] = ESC;
+    buf[1] = b'[';
+    for i in 0..count {
+        buf[2 + i] = digits[count - 1 - i];
+    }
+    buf[2 + count] = command;
+
+    &buf[..3 + count]
+}
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+/// Gets the start of the last UTF-8 character in `line`.
+fn char_start(line: &[u8]) -> usize {
+    line.iter().rposition(|&b| !is_continuation(b)).unwrap_or(0)
+}
+
+forward_usb_class! {
+    impl<B, L, H, RS, WS> UsbClass<B> for Console<'_, B, L, H, RS, WS>
+    where
+        B: UsbBus,
+        L: BorrowMut<[u8]>,
+        H: BorrowMut<[u8]>,
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        self.port;
+
+        fn reset(&mut self) {
+            self.input = InputState::Normal;
+            self.line_len = 0;
+            self.line_ready = false;
+            self.recall = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_bus::{self, TestBus, TestHost, read_ep, write_ep};
+    use std::vec::Vec;
+
+    fn echoed<B, L, H>(console: &mut Console<B, L, H>, host: &TestHost) -> Vec<u8>
+    where
+        B: UsbBus,
+        L: BorrowMut<[u8]>,
+        H: BorrowMut<[u8]>,
+    {
+        let mut data = Vec::new();
+
+        loop {
+            console.port_mut().flush().ok();
+
+            match host.read(write_ep()) {
+                Some(packet) => data.extend_from_slice(&packet),
+                None => return data,
+            }
+        }
+    }
+
+    #[test]
+    fn line_editing() {
+        let (alloc, host) = TestBus::new();
+        let mut console = Console::new(SerialPort::new(&alloc), [0u8; 8], [0u8; 16]);
+        let _dev = test_bus::device(&alloc);
+
+        host.write(read_ep(), b"ab\x7fc\r\nx\x03");
+        assert_eq!(console.read_line().unwrap(), b"ac");
+        assert_eq!(console.read_line(), Err(UsbError::WouldBlock));
+        assert!(console.take_interrupt());
+        assert!(!console.take_interrupt());
+        assert_eq!(echoed(&mut console, &host), b"ab\x08 \x08c\r\nx^C\r\n");
+
+        // Lines are truncated to the line buffer, and recalled from the history
+        host.write(read_ep(), b"0123456789\r\x1b[A\x1b[A\x1b[A\x1b[B\n");
+        assert_eq!(console.read_line().unwrap(), b"01234567");
+        assert_eq!(console.read_line().unwrap(), b"01234567");
+        assert_eq!(
+            echoed(&mut console, &host),
+            b"01234567\x07\x07\r\n\x1b[K01234567\x1b[8D\x1b[Kac\x1b[2D\x1b[K01234567\r\n"
+        );
+
+        console.set_mode(ConsoleMode::Raw);
+        assert_eq!(console.read_line(), Err(UsbError::InvalidState));
+        host.write(read_ep(), b"\r\x03");
+        let mut buf = [0u8; 8];
+        assert_eq!(console.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"\r\x03");
+        assert!(console.take_interrupt());
+    }
+
+    #[test]
+    fn paste() {
+        let (alloc, host) = TestBus::new();
+        let mut console = Console::new(SerialPort::new(&alloc), [0u8; 64], [0u8; 0]);
+        let _dev = test_bus::device(&alloc);
+
+        let pasted: Vec<u8> = (0..40).flat_map(|i| [b'a' + (i % 26), b'\r']).collect();
+        for packet in pasted.chunks(16) {
+            host.write(read_ep(), packet);
+        }
+
+        let mut lines = Vec::new();
+        while let Ok(line) = console.read_line() {
+            lines.extend_from_slice(line);
+            echoed(&mut console, &host);
+        }
+
+        assert_eq!(lines, (0..40).map(|i| b'a' + (i % 26)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cooked_write() {
+        let (alloc, host) = TestBus::new();
+        let mut console = Console::new(SerialPort::new(&alloc), [0u8; 8], [0u8; 0]);
+        let _dev = test_bus::device(&alloc);
+
+        assert_eq!(console.write(b"a\nb\n"), Ok(4));
+        assert_eq!(echoed(&mut console, &host), b"a\r\nb\r\n");
+    }
+}
@@ -0,0 +1,538 @@
+use crate::buffer::DefaultBufferStore;
+use crate::serial_port::SerialPort;
+use crate::wrapper::forward_usb_class;
+use core::borrow::BorrowMut;
+use core::convert::Infallible;
+use usb_device::Result;
+use usb_device::class_prelude::*;
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+/// Encoding used to delimit frames in the byte stream
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FrameEncoding {
+    /// Consistent Overhead Byte Stuffing. Frames are terminated by a zero byte, which never occurs
+    /// inside an encoded frame.
+    Cobs,
+
+    /// Serial Line Internet Protocol (RFC 1055). Frames are delimited by `0xc0` bytes on both
+    /// ends, and `0xc0` and `0xdb` inside a frame are escaped.
+    Slip,
+}
+
+impl FrameEncoding {
+    /// Gets the encoded length of a frame including delimiters.
+    pub fn encoded_len(&self, frame: &[u8]) -> usize {
+        match self {
+            FrameEncoding::Cobs => {
+                let mut len = 2;
+                let mut code = 1;
+
+                for &b in frame {
+                    if b != 0 {
+                        len += 1;
+                        code += 1;
+                    }
+
+                    if b == 0 || code == 0xff {
+                        len += 1;
+                        code = 1;
+                    }
+                }
+
+                len
+            }
+            FrameEncoding::Slip => {
+                2 + frame.len()
+                    + frame
+                        .iter()
+                        .filter(|&&b| b == SLIP_END || b == SLIP_ESC)
+                        .count()
+            }
+        }
+    }
+
+    /// Encodes a frame including delimiters into `out`, and returns the number of bytes written.
+    /// `out` must be at least [`encoded_len`](FrameEncoding::encoded_len) bytes long.
+    pub fn encode(&self, frame: &[u8], out: &mut [u8]) -> usize {
+        match self {
+            FrameEncoding::Cobs => {
+                let mut code_pos = 0;
+                let mut pos = 1;
+                let mut code = 1;
+
+                for &b in frame {
+                    if b != 0 {
+                        out[pos] = b;
+                        pos += 1;
+                        code += 1;
+                    }
+
+                    if b == 0 || code == 0xff {
+                        out[code_pos] = code;
+                        code_pos = pos;
+                        pos += 1;
+                        code = 1;
+                    }
+                }
+
+                out[code_pos] = code;
+                out[pos] = 0;
+                pos + 1
+            }
+            FrameEncoding::Slip => {
+                let mut pos = 0;
+                let mut put = |b| {
+                    out[pos] = b;
+                    pos += 1;
+                };
+
+                put(SLIP_END);
+
+                for &b in frame {
+                    match b {
+                        SLIP_END => {
+                            put(SLIP_ESC);
+                            put(SLIP_ESC_END);
+                        }
+                        SLIP_ESC => {
+                            put(SLIP_ESC);
+                            put(SLIP_ESC_ESC);
+                        }
+                        b => put(b),
+                    }
+                }
+
+                put(SLIP_END);
+                pos
+            }
+        }
+    }
+}
+
+/// Counters for received frames that were dropped
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct FrameErrors {
+    /// Frames that were longer than the maximum frame size or the receive buffer
+    pub oversize: u32,
+
+    /// Frames that were not validly encoded
+    pub malformed: u32,
+}
+
+/// Incremental frame decoder that decodes directly into the caller's buffer.
+pub(crate) struct FrameDecoder {
+    encoding: FrameEncoding,
+    len: usize,
+    cobs_code: u8,
+    cobs_remaining: u8,
+    slip_escape: bool,
+    discarding: bool,
+    errors: FrameErrors,
+}
+
+impl FrameDecoder {
+    pub fn new(encoding: FrameEncoding) -> Self {
+        FrameDecoder {
+            encoding,
+            len: 0,
+            cobs_code: 0,
+            cobs_remaining: 0,
+            slip_escape: false,
+            discarding: false,
+            errors: FrameErrors::default(),
+        }
+    }
+
+    pub fn errors(&self) -> FrameErrors {
+        self.errors
+    }
+
+    /// Discards any partially decoded frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.cobs_code = 0;
+        self.cobs_remaining = 0;
+        self.slip_escape = false;
+        self.discarding = false;
+    }
+
+    /// Decodes one byte into `buf` and returns the length of the frame if the byte completed one.
+    /// Decoded data is limited to `buf.len()` bytes, and `buf` must be the same buffer on every
+    /// call until a frame is complete.
+    pub fn push(&mut self, b: u8, buf: &mut [u8]) -> Option<usize> {
+        let delimiter = match self.encoding {
+            FrameEncoding::Cobs => 0,
+            FrameEncoding::Slip => SLIP_END,
+        };
+
+        if b == delimiter {
+            let complete = !self.discarding
+                && match self.encoding {
+                    FrameEncoding::Cobs => self.cobs_code != 0 && self.cobs_remaining == 0,
+                    FrameEncoding::Slip => self.len != 0 && !self.slip_escape,
+                };
+
+            let len = self.len;
+            let empty = len == 0 && self.cobs_code == 0 && !self.slip_escape;
+
+            if !complete && !self.discarding && !empty {
+                self.errors.malformed = self.errors.malformed.wrapping_add(1);
+            }
+
+            self.reset();
+
+            return if complete { Some(len) } else { None };
+        }
+
+        if self.discarding {
+            return None;
+        }
+
+        let data = match self.encoding {
+            FrameEncoding::Cobs if self.cobs_remaining == 0 => {
+                let zero = self.cobs_code != 0 && self.cobs_code != 0xff;
+                self.cobs_code = b;
+                self.cobs_remaining = b - 1;
+
+                if !zero {
+                    return None;
+                }
+
+                0
+            }
+            FrameEncoding::Cobs => {
+                self.cobs_remaining -= 1;
+                b
+            }
+            FrameEncoding::Slip if self.slip_escape => {
+                self.slip_escape = false;
+
+                match b {
+                    SLIP_ESC_END => SLIP_END,
+                    SLIP_ESC_ESC => SLIP_ESC,
+                    _ => {
+                        self.errors.malformed = self.errors.malformed.wrapping_add(1);
+                        self.discarding = true;
+                        return None;
+                    }
+                }
+            }
+            FrameEncoding::Slip if b == SLIP_ESC => {
+                self.slip_escape = true;
+                return None;
+            }
+            FrameEncoding::Slip => b,
+        };
+
+        if self.len >= buf.len() {
+            self.errors.oversize = self.errors.oversize.wrapping_add(1);
+            self.discarding = true;
+            return None;
+        }
+
+        buf[self.len] = data;
+        self.len += 1;
+
+        None
+    }
+}
+
+/// Packet transport over a [`SerialPort`] using COBS or SLIP framing.
+///
+/// Frames are encoded directly into the write buffer of the port and decoded directly from its
+/// read buffer into the caller's buffer, without intermediate copies. Received frames that are
+/// oversized or malformed are dropped and counted in [`errors`](FramedPort::errors).
+///
+/// `UsbDevice::poll` takes the framed port itself, which drops a partially received frame when the
+/// bus is reset.
+pub struct FramedPort<'a, B, RS = DefaultBufferStore, WS = DefaultBufferStore>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    port: SerialPort<'a, B, RS, WS>,
+    encoding: FrameEncoding,
+    max_frame_size: usize,
+    decoder: FrameDecoder,
+}
+
+impl<'a, B, RS, WS> FramedPort<'a, B, RS, WS>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    /// Creates a new framed port with the given encoding and maximum decoded frame size in bytes.
+    pub fn new(
+        port: SerialPort<'a, B, RS, WS>,
+        encoding: FrameEncoding,
+        max_frame_size: usize,
+    ) -> Self {
+        FramedPort {
+            port,
+            encoding,
+            max_frame_size,
+            decoder: FrameDecoder::new(encoding),
+        }
+    }
+
+    /// Gets the serial port.
+    pub fn port(&self) -> &SerialPort<'a, B, RS, WS> {
+        &self.port
+    }
+
+    /// Mutably gets the serial port. Reading from or writing to the port directly will corrupt the
+    /// frame stream.
+    pub fn port_mut(&mut self) -> &mut SerialPort<'a, B, RS, WS> {
+        &mut self.port
+    }
+
+    /// Destroys the framed port and returns the serial port.
+    pub fn release(self) -> SerialPort<'a, B, RS, WS> {
+        self.port
+    }
+
+    /// Gets the counters for dropped received frames.
+    pub fn errors(&self) -> FrameErrors {
+        self.decoder.errors()
+    }
+
+    /// Encodes and queues a frame for sending. The frame is either queued completely or not at
+    /// all, so frames are never interleaved or truncated.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There is not enough room in the write
+    ///   buffer for the encoded frame.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - The frame is longer than the
+    ///   maximum frame size, or the encoded frame is larger than the whole write buffer.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn send_frame(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() > self.max_frame_size {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let encoding = self.encoding;
+        self.port
+            .write_all_with(encoding.encoded_len(frame), |buf| {
+                encoding.encode(frame, buf);
+            })?;
+
+        match self.port.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Receives a frame into `buf` and returns its length. Frames longer than the maximum frame
+    /// size or `buf` are dropped.
+    ///
+    /// A partially received frame is kept in `buf`, so the same buffer must be passed on every call
+    /// until a frame has been received.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No complete frame has been received.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn recv_frame(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let max = core::cmp::min(self.max_frame_size, buf.len());
+        let buf = &mut buf[..max];
+
+        loop {
+            self.port.poll()?;
+
+            if self.port.read_buf.available_read() == 0 {
+                return Err(UsbError::WouldBlock);
+            }
+
+            let decoder = &mut self.decoder;
+            let mut frame = None;
+
+            self.port
+                .read_buf
+                .read(usize::MAX, |data| {
+                    for (i, &b) in data.iter().enumerate() {
+                        if let Some(len) = decoder.push(b, buf) {
+                            frame = Some(len);
+                            return Ok(i + 1);
+                        }
+                    }
+
+                    Ok::<usize, Infallible>(data.len())
+                })
+                .ok();
+
+            if let Some(len) = frame {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+forward_usb_class! {
+    impl<B, RS, WS> UsbClass<B> for FramedPort<'_, B, RS, WS>
+    where
+        B: UsbBus,
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        self.port;
+
+        fn reset(&mut self) {
+            self.decoder.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::{self, TestBus, read_ep, write_ep};
+
+    fn encode(encoding: FrameEncoding, frame: &[u8], out: &mut [u8]) -> usize {
+        let len = encoding.encode(frame, out);
+        assert_eq!(len, encoding.encoded_len(frame));
+        len
+    }
+
+    fn decode(
+        encoding: FrameEncoding,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> (Option<usize>, FrameErrors) {
+        let mut decoder = FrameDecoder::new(encoding);
+        let mut frame = None;
+
+        for &b in data {
+            if let Some(len) = decoder.push(b, buf) {
+                frame = Some(len);
+            }
+        }
+
+        (frame, decoder.errors())
+    }
+
+    #[test]
+    fn cobs() {
+        let mut out = [0u8; 16];
+
+        let len = encode(FrameEncoding::Cobs, &[0x11, 0x00, 0x00, 0x22], &mut out);
+        assert_eq!(&out[..len], &[0x02, 0x11, 0x01, 0x02, 0x22, 0x00]);
+
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            decode(FrameEncoding::Cobs, &out[..len], &mut buf).0,
+            Some(4)
+        );
+        assert_eq!(&buf[..4], &[0x11, 0x00, 0x00, 0x22]);
+
+        let len = encode(FrameEncoding::Cobs, &[], &mut out);
+        assert_eq!(&out[..len], &[0x01, 0x00]);
+    }
+
+    #[test]
+    fn cobs_long_run() {
+        let frame = [0x55u8; 300];
+        let mut out = [0u8; 310];
+        let len = encode(FrameEncoding::Cobs, &frame, &mut out);
+        assert_eq!(len, 300 + 2 + 1);
+        assert_eq!(out[0], 0xff);
+        assert_eq!(out[255], 47);
+
+        let mut buf = [0u8; 300];
+        assert_eq!(
+            decode(FrameEncoding::Cobs, &out[..len], &mut buf).0,
+            Some(300)
+        );
+        assert_eq!(buf, frame);
+    }
+
+    #[test]
+    fn slip() {
+        let mut out = [0u8; 16];
+
+        let len = encode(FrameEncoding::Slip, &[0x01, 0xc0, 0xdb], &mut out);
+        assert_eq!(&out[..len], &[0xc0, 0x01, 0xdb, 0xdc, 0xdb, 0xdd, 0xc0]);
+
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            decode(FrameEncoding::Slip, &out[..len], &mut buf).0,
+            Some(3)
+        );
+        assert_eq!(&buf[..3], &[0x01, 0xc0, 0xdb]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut buf = [0u8; 2];
+
+        let (frame, errors) = decode(FrameEncoding::Slip, &[0xc0, 1, 2, 3, 0xc0], &mut buf);
+        assert_eq!(frame, None);
+        assert_eq!(errors.oversize, 1);
+
+        let (frame, errors) = decode(FrameEncoding::Slip, &[0xc0, 0xdb, 0x01, 0xc0], &mut buf);
+        assert_eq!(frame, None);
+        assert_eq!(errors.malformed, 1);
+
+        let (frame, errors) = decode(FrameEncoding::Cobs, &[0x03, 0x01, 0x00], &mut buf);
+        assert_eq!(frame, None);
+        assert_eq!(errors.malformed, 1);
+
+        // A valid frame after an error is still received
+        let (frame, _) = decode(
+            FrameEncoding::Cobs,
+            &[0x05, 0x00, 0x02, 0x01, 0x00],
+            &mut buf,
+        );
+        assert_eq!(frame, Some(1));
+    }
+
+    #[test]
+    fn round_trip() {
+        let (alloc, host) = TestBus::new();
+        let mut framed = FramedPort::new(SerialPort::new(&alloc), FrameEncoding::Cobs, 128);
+        let _dev = test_bus::device(&alloc);
+
+        let mut frame = [0u8; 100];
+        for (i, b) in frame.iter_mut().enumerate() {
+            *b = (i % 7) as u8;
+        }
+
+        assert_eq!(framed.send_frame(&[0x11, 0x00, 0x22]), Ok(()));
+        let short = host.read(write_ep()).unwrap();
+        assert_eq!(short, [0x02, 0x11, 0x02, 0x22, 0x00]);
+
+        // The encoded frame is longer than a packet and is sent in two parts
+        assert_eq!(framed.send_frame(&frame), Ok(()));
+        let first = host.read(write_ep()).unwrap();
+        assert_eq!(first.len(), 64);
+        framed.port_mut().flush().ok();
+        let second = host.read(write_ep()).unwrap();
+        assert_eq!(
+            first.len() + second.len(),
+            FrameEncoding::Cobs.encoded_len(&frame)
+        );
+
+        let mut buf = [0u8; 128];
+        assert_eq!(framed.recv_frame(&mut buf), Err(UsbError::WouldBlock));
+
+        host.write(read_ep(), &short);
+        assert_eq!(framed.recv_frame(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], &[0x11, 0x00, 0x22]);
+
+        host.write(read_ep(), &first);
+        assert_eq!(framed.recv_frame(&mut buf), Err(UsbError::WouldBlock));
+        host.write(read_ep(), &second);
+        assert_eq!(framed.recv_frame(&mut buf), Ok(100));
+        assert_eq!(&buf[..100], &frame);
+
+        assert_eq!(framed.errors(), FrameErrors::default());
+    }
+}
@@ -0,0 +1,870 @@
+use crate::buffer::DefaultBufferStore;
+use crate::clock::{self, Clock};
+use crate::serial_port::SerialPort;
+use crate::wrapper::forward_usb_class;
+use core::borrow::BorrowMut;
+use core::convert::Infallible;
+use core::iter;
+use usb_device::Result;
+use usb_device::class_prelude::*;
+
+const FLAG: u8 = 0x7e;
+const ESCAPE: u8 = 0x7d;
+const ESCAPE_XOR: u8 = 0x20;
+
+/// Control field of an unnumbered information frame, which is not acknowledged.
+const CONTROL_UI: u8 = 0x03;
+
+/// Control field of a receive ready frame, which acknowledges information frames. N(R) is stored
+/// in the upper 3 bits.
+const CONTROL_RR: u8 = 0x01;
+
+/// Control field of a set asynchronous balanced mode frame, which starts the sequence numbers of
+/// the sender's direction over.
+const CONTROL_SABM: u8 = 0x2f;
+
+/// Control field of an unnumbered acknowledgement frame, which acknowledges a set asynchronous
+/// balanced mode frame.
+const CONTROL_UA: u8 = 0x63;
+
+const SEQ_MASK: u8 = 0x07;
+
+/// Maximum length of a decoded receive ready frame, which is the control field and a 32-bit FCS.
+const MAX_ACK_LEN: usize = 1 + 4;
+
+/// Frame check sequence appended to each frame
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Fcs {
+    /// 16-bit FCS (CRC-16/CCITT as used by HDLC and X.25)
+    Crc16,
+
+    /// 32-bit FCS (CRC-32 as used by HDLC and PPP)
+    Crc32,
+}
+
+impl Fcs {
+    /// Gets the length of the FCS in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Fcs::Crc16 => 2,
+            Fcs::Crc32 => 4,
+        }
+    }
+
+    /// Computes the FCS of `data`. The FCS is transmitted least significant byte first.
+    pub fn compute(&self, data: impl IntoIterator<Item = u8>) -> u32 {
+        let (poly, init) = match self {
+            Fcs::Crc16 => (0x8408, 0xffff),
+            Fcs::Crc32 => (0xedb8_8320, 0xffff_ffff),
+        };
+
+        let mut crc: u32 = init;
+        for b in data {
+            crc ^= b as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ poly
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+
+        !crc & init
+    }
+}
+
+/// Retransmission parameters for reliable delivery
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Retransmit {
+    timeout_ms: u32,
+    max_retries: u8,
+}
+
+impl Retransmit {
+    /// Creates retransmission parameters. A frame is sent again if it hasn't been acknowledged
+    /// within `timeout_ms`, and dropped after `max_retries` retransmissions.
+    pub const fn new(timeout_ms: u32, max_retries: u8) -> Self {
+        Retransmit {
+            timeout_ms,
+            max_retries,
+        }
+    }
+
+    /// Gets the acknowledgement timeout in milliseconds.
+    pub fn timeout_ms(&self) -> u32 {
+        self.timeout_ms
+    }
+
+    /// Gets the maximum number of retransmissions.
+    pub fn max_retries(&self) -> u8 {
+        self.max_retries
+    }
+}
+
+/// Error counters of an [`HdlcPort`]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct HdlcErrors {
+    /// Received frames dropped due to an FCS mismatch
+    pub fcs: u32,
+
+    /// Received frames dropped because they didn't fit into the receive store
+    pub oversize: u32,
+
+    /// Received frames dropped because they were aborted, too short or had an unknown control
+    /// field
+    pub malformed: u32,
+
+    /// Frames that were sent again because they were not acknowledged in time
+    pub retransmissions: u32,
+
+    /// Frames that were dropped because they were not acknowledged after all retransmissions. The
+    /// peer may or may not have received a dropped frame, so the link is reset before the next
+    /// frame is sent.
+    pub failed: u32,
+}
+
+#[derive(Copy, Clone)]
+enum TxState {
+    Idle,
+    AwaitingAck {
+        sent_at: u32,
+        retries: u8,
+    },
+
+    /// A frame has been dropped and the link is being reset. `sent_at` is `None` until the reset
+    /// frame has been written.
+    Resetting {
+        sent_at: Option<u32>,
+    },
+}
+
+#[derive(Default)]
+struct Decoder {
+    len: usize,
+    escape: bool,
+    overflow: bool,
+
+    /// The current frame is decoded into the acknowledgement store.
+    ack_only: bool,
+}
+
+impl Decoder {
+    fn reset(&mut self) {
+        *self = Decoder::default();
+    }
+
+    /// Returns `true` if no frame is being decoded.
+    fn is_idle(&self) -> bool {
+        self.len == 0 && !self.escape && !self.overflow
+    }
+
+    /// Decodes one byte into `store` and returns the length of the frame if the byte completed
+    /// one.
+    fn push(&mut self, b: u8, store: &mut [u8], errors: &mut HdlcErrors) -> Option<usize> {
+        match b {
+            FLAG => {
+                let len = self.len;
+
+                if self.overflow {
+                    errors.oversize = errors.oversize.wrapping_add(1);
+                } else if self.escape {
+                    errors.malformed = errors.malformed.wrapping_add(1);
+                }
+
+                let complete = !self.overflow && !self.escape && len > 0;
+                self.reset();
+
+                complete.then_some(len)
+            }
+            _ if self.overflow => None,
+            ESCAPE if !self.escape => {
+                self.escape = true;
+                None
+            }
+            b => {
+                let b = if self.escape { b ^ ESCAPE_XOR } else { b };
+                self.escape = false;
+
+                if self.len >= store.len() {
+                    self.overflow = true;
+                } else {
+                    store[self.len] = b;
+                    self.len += 1;
+                }
+
+                None
+            }
+        }
+    }
+}
+
+/// HDLC-like framed transport over a [`SerialPort`] with optional reliable delivery.
+///
+/// Each frame is delimited by `0x7e` flags, with `0x7e` and `0x7d` inside the frame escaped as
+/// `0x7d` followed by the byte XORed with `0x20`. A frame consists of a one byte control field,
+/// the payload and the FCS:
+///
+/// * Information frames (`0bRRR0SSS0` with send sequence number `SSS`) are used for reliable
+///   delivery, and are acknowledged by the receiver.
+/// * Unnumbered information frames (`0x03`) are used if retransmission is disabled, and are not
+///   acknowledged.
+/// * Receive ready frames (`0bRRR00001`) acknowledge information frames, with `RRR` being the
+///   next expected sequence number. They carry no payload.
+/// * Set asynchronous balanced mode frames (`0x2f`) start the sequence numbers of the sender's
+///   direction over, and are acknowledged with unnumbered acknowledgement frames (`0x63`). They
+///   carry no payload either.
+///
+/// With retransmission enabled, one frame at a time is in flight, and it's sent again if it isn't
+/// acknowledged within the timeout. Duplicate information frames are acknowledged again and
+/// dropped. Both kinds of data frames are always accepted on receive. While a received frame is
+/// waiting to be read, acknowledgements are still processed, and data frames are dropped without
+/// acknowledgement so that the peer sends them again.
+///
+/// A frame that is still not acknowledged after all retransmissions is dropped. The peer may have
+/// received it with only the acknowledgements being lost, so the link is reset with a set
+/// asynchronous balanced mode frame before the next frame is sent. The reset frame is sent again
+/// after every timeout until it's acknowledged.
+///
+/// The payload of the frame in flight is kept in the transmit store, and received frames are
+/// decoded into the receive store, so the stores limit the maximum frame size. Timeouts are driven
+/// by the supplied [`Clock`], and [`poll`](HdlcPort::poll) should be called regularly after
+/// `UsbDevice::poll` so that acknowledgements are processed and frames are retransmitted in time.
+///
+/// A bus reset drops the frame in flight and starts both sequence numbers over, so the HDLC port
+/// rather than the bare serial port is what gets passed to `UsbDevice::poll`.
+pub struct HdlcPort<'a, B, C, S, RS = DefaultBufferStore, WS = DefaultBufferStore>
+where
+    B: UsbBus,
+    C: Clock,
+    S: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    port: SerialPort<'a, B, RS, WS>,
+    clock: C,
+    fcs: Fcs,
+    retransmit: Option<Retransmit>,
+    tx_store: S,
+    tx_len: usize,
+    tx_seq: u8,
+    tx: TxState,
+    rx_store: S,
+    rx_seq: u8,
+    rx_ready: Option<usize>,
+    ack_store: [u8; MAX_ACK_LEN],
+    decoder: Decoder,
+    ack: Option<u8>,
+    reset_ack: bool,
+    errors: HdlcErrors,
+}
+
+impl<'a, B, C, S, RS, WS> HdlcPort<'a, B, C, S, RS, WS>
+where
+    B: UsbBus,
+    C: Clock,
+    S: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    /// Creates a new HDLC port. If `retransmit` is `None`, frames are sent without acknowledgement.
+    pub fn new(
+        port: SerialPort<'a, B, RS, WS>,
+        clock: C,
+        fcs: Fcs,
+        retransmit: Option<Retransmit>,
+        tx_store: S,
+        rx_store: S,
+    ) -> Self {
+        HdlcPort {
+            port,
+            clock,
+            fcs,
+            retransmit,
+            tx_store,
+            tx_len: 0,
+            tx_seq: 0,
+            tx: TxState::Idle,
+            rx_store,
+            rx_seq: 0,
+            rx_ready: None,
+            ack_store: [0; MAX_ACK_LEN],
+            decoder: Decoder::default(),
+            ack: None,
+            reset_ack: false,
+            errors: HdlcErrors::default(),
+        }
+    }
+
+    /// Gets the serial port.
+    pub fn port(&self) -> &SerialPort<'a, B, RS, WS> {
+        &self.port
+    }
+
+    /// Mutably gets the serial port. Reading from or writing to the port directly will corrupt the
+    /// frame stream.
+    pub fn port_mut(&mut self) -> &mut SerialPort<'a, B, RS, WS> {
+        &mut self.port
+    }
+
+    /// Destroys the HDLC port and returns the serial port, clock and the transmit and receive
+    /// stores.
+    pub fn release(self) -> (SerialPort<'a, B, RS, WS>, C, S, S) {
+        (self.port, self.clock, self.tx_store, self.rx_store)
+    }
+
+    /// Gets the error counters.
+    pub fn errors(&self) -> HdlcErrors {
+        self.errors
+    }
+
+    /// Returns `true` if a frame has been sent and is waiting to be acknowledged, or if the link is
+    /// being reset after a frame was dropped. New frames can only be sent when this returns
+    /// `false`.
+    pub fn is_sending(&self) -> bool {
+        !matches!(self.tx, TxState::Idle)
+    }
+
+    /// Sends a frame. The frame is either queued completely or not at all.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There is not enough room in the write
+    ///   buffer, the previous frame has not been acknowledged yet, or the link is being reset.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - The frame doesn't fit into the
+    ///   transmit store, or the encoded frame is larger than the whole write buffer.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn send_frame(&mut self, payload: &[u8]) -> Result<()> {
+        if self.retransmit.is_none() {
+            return match write_frame(&mut self.port, self.fcs, CONTROL_UI, payload)? {
+                true => Ok(()),
+                false => Err(UsbError::WouldBlock),
+            };
+        }
+
+        if payload.len() > self.tx_store.borrow().len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        if self.is_sending() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        if !write_frame(&mut self.port, self.fcs, self.tx_seq << 1, payload)? {
+            return Err(UsbError::WouldBlock);
+        }
+
+        self.tx_store.borrow_mut()[..payload.len()].copy_from_slice(payload);
+        self.tx_len = payload.len();
+        self.tx = TxState::AwaitingAck {
+            sent_at: self.clock.now_ms(),
+            retries: 0,
+        };
+
+        Ok(())
+    }
+
+    /// Receives a frame and returns its payload, which is borrowed from the receive store.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No frame has been received.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn recv_frame(&mut self) -> Result<&[u8]> {
+        self.poll()?;
+
+        match self.rx_ready.take() {
+            Some(len) => Ok(&self.rx_store.borrow()[1..len - self.fcs.size()]),
+            None => Err(UsbError::WouldBlock),
+        }
+    }
+
+    /// Processes received frames and acknowledgements, and retransmits the frame in flight if it
+    /// has timed out. Without retransmission, reception stops while a received frame is waiting to
+    /// be read with [`recv_frame`](HdlcPort::recv_frame).
+    pub fn poll(&mut self) -> Result<()> {
+        match self.port.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => {}
+            Err(err) => return Err(err),
+        }
+
+        self.receive()?;
+
+        if self.reset_ack && write_frame(&mut self.port, self.fcs, CONTROL_UA, &[])? {
+            self.reset_ack = false;
+        }
+
+        if let Some(nr) = self.ack
+            && write_frame(&mut self.port, self.fcs, CONTROL_RR | (nr << 5), &[])?
+        {
+            self.ack = None;
+        }
+
+        let Some(retransmit) = self.retransmit else {
+            return Ok(());
+        };
+
+        if let TxState::AwaitingAck { sent_at, retries } = self.tx
+            && clock::elapsed_ms(&mut self.clock, sent_at) >= retransmit.timeout_ms
+        {
+            if retries >= retransmit.max_retries {
+                // The peer may have received the frame with only the acknowledgements being lost,
+                // in which case it would drop the next frame with the same sequence number as a
+                // duplicate, so both sides start over.
+                self.tx_seq = 0;
+                self.tx = TxState::Resetting { sent_at: None };
+                self.errors.failed = self.errors.failed.wrapping_add(1);
+            } else if write_frame(
+                &mut self.port,
+                self.fcs,
+                self.tx_seq << 1,
+                &self.tx_store.borrow()[..self.tx_len],
+            )? {
+                self.tx = TxState::AwaitingAck {
+                    sent_at: self.clock.now_ms(),
+                    retries: retries + 1,
+                };
+                self.errors.retransmissions = self.errors.retransmissions.wrapping_add(1);
+            }
+        }
+
+        if let TxState::Resetting { sent_at } = self.tx
+            && sent_at
+                .is_none_or(|t| clock::elapsed_ms(&mut self.clock, t) >= retransmit.timeout_ms)
+            && write_frame(&mut self.port, self.fcs, CONTROL_SABM, &[])?
+        {
+            self.tx = TxState::Resetting {
+                sent_at: Some(self.clock.now_ms()),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<()> {
+        loop {
+            // While a received frame is waiting, new frames are decoded into the acknowledgement
+            // store, which is too small for data frames.
+            let blocked = self.rx_ready.is_some();
+
+            if blocked && self.retransmit.is_none() {
+                break;
+            }
+
+            self.port.poll()?;
+
+            if self.port.read_buf.available_read() == 0 {
+                break;
+            }
+
+            let mut frame = None;
+            let Self {
+                port,
+                rx_store,
+                ack_store,
+                decoder,
+                errors,
+                ..
+            } = self;
+            let rx_store = rx_store.borrow_mut();
+
+            port.read_buf
+                .read(usize::MAX, |data| {
+                    for (i, &b) in data.iter().enumerate() {
+                        if decoder.is_idle() {
+                            decoder.ack_only = blocked;
+                        }
+
+                        let ack_only = decoder.ack_only;
+                        let len = if ack_only {
+                            // Dropped data frames are not errors
+                            decoder.push(b, ack_store, &mut HdlcErrors::default())
+                        } else {
+                            decoder.push(b, rx_store, errors)
+                        };
+
+                        if let Some(len) = len {
+                            frame = Some((len, ack_only));
+                            return Ok(i + 1);
+                        }
+                    }
+
+                    Ok::<usize, Infallible>(data.len())
+                })
+                .ok();
+
+            if let Some((len, ack_only)) = frame {
+                self.handle_frame(len, ack_only);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, len: usize, ack_only: bool) {
+        let fcs_len = self.fcs.size();
+        let data = if ack_only {
+            &self.ack_store[..len]
+        } else {
+            &self.rx_store.borrow()[..len]
+        };
+
+        if len < 1 + fcs_len {
+            self.errors.malformed = self.errors.malformed.wrapping_add(1);
+            return;
+        }
+
+        let (body, fcs) = data.split_at(len - fcs_len);
+        if fcs != &self.fcs.compute(body.iter().copied()).to_le_bytes()[..fcs_len] {
+            self.errors.fcs = self.errors.fcs.wrapping_add(1);
+            return;
+        }
+
+        let control = body[0];
+
+        if control & 0x1f == CONTROL_RR && body.len() == 1 {
+            let nr = control >> 5;
+
+            // Acknowledgements that arrive while the link is being reset are stale.
+            if matches!(self.tx, TxState::AwaitingAck { .. }) && nr == (self.tx_seq + 1) & SEQ_MASK
+            {
+                self.tx_seq = nr;
+                self.tx = TxState::Idle;
+            }
+        } else if control == CONTROL_SABM && body.len() == 1 {
+            // Any pending acknowledgement refers to the old sequence numbers.
+            self.rx_seq = 0;
+            self.ack = None;
+            self.reset_ack = true;
+        } else if control == CONTROL_UA && body.len() == 1 {
+            if matches!(self.tx, TxState::Resetting { .. }) {
+                self.tx = TxState::Idle;
+            }
+        } else if ack_only {
+            // Data frames are not acknowledged while a received frame is waiting, and are sent
+            // again by the peer.
+        } else if control & 0x01 == 0 {
+            // Information frame
+            if (control >> 1) & SEQ_MASK == self.rx_seq {
+                self.rx_seq = (self.rx_seq + 1) & SEQ_MASK;
+                self.rx_ready = Some(len);
+            }
+
+            // Duplicates are acknowledged again in case the previous acknowledgement was lost.
+            self.ack = Some(self.rx_seq);
+        } else if control == CONTROL_UI {
+            self.rx_ready = Some(len);
+        } else {
+            self.errors.malformed = self.errors.malformed.wrapping_add(1);
+        }
+    }
+}
+
+/// Encodes a frame into the write buffer of the port. Returns `false` if there was not enough room.
+fn write_frame<B, RS, WS>(
+    port: &mut SerialPort<'_, B, RS, WS>,
+    fcs: Fcs,
+    control: u8,
+    payload: &[u8],
+) -> Result<bool>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    let check = fcs
+        .compute(iter::once(control).chain(payload.iter().copied()))
+        .to_le_bytes();
+    let body = iter::once(control)
+        .chain(payload.iter().copied())
+        .chain(check[..fcs.size()].iter().copied());
+
+    let len = 2 + body
+        .clone()
+        .map(|b| if b == FLAG || b == ESCAPE { 2 } else { 1 })
+        .sum::<usize>();
+
+    let written = port.write_all_with(len, |out| {
+        let mut pos = 0;
+        let mut put = |b| {
+            out[pos] = b;
+            pos += 1;
+        };
+
+        put(FLAG);
+        for b in body {
+            if b == FLAG || b == ESCAPE {
+                put(ESCAPE);
+                put(b ^ ESCAPE_XOR);
+            } else {
+                put(b);
+            }
+        }
+        put(FLAG);
+    });
+
+    match written {
+        Ok(()) => {}
+        Err(UsbError::WouldBlock) => return Ok(false),
+        Err(err) => return Err(err),
+    }
+
+    match port.flush() {
+        Ok(()) | Err(UsbError::WouldBlock) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+forward_usb_class! {
+    impl<B, C, S, RS, WS> UsbClass<B> for HdlcPort<'_, B, C, S, RS, WS>
+    where
+        B: UsbBus,
+        C: Clock,
+        S: BorrowMut<[u8]>,
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        self.port;
+
+        fn reset(&mut self) {
+            self.decoder.reset();
+            self.tx = TxState::Idle;
+            self.tx_seq = 0;
+            self.rx_seq = 0;
+            self.rx_ready = None;
+            self.ack = None;
+            self.reset_ack = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::{self, TestBus, TestHost, read_ep, write_ep};
+    use core::cell::Cell;
+
+    #[test]
+    fn fcs() {
+        assert_eq!(Fcs::Crc16.compute(*b"123456789"), 0x906e);
+        assert_eq!(Fcs::Crc32.compute(*b"123456789"), 0xcbf4_3926);
+    }
+
+    /// Moves a packet from the IN endpoint of one device to the OUT endpoint of another, optionally
+    /// dropping it. Returns `true` if there was a packet.
+    fn transfer(from: &TestHost, to: &TestHost, drop: bool) -> bool {
+        match from.read(write_ep()) {
+            Some(packet) => {
+                if !drop {
+                    to.write(read_ep(), &packet);
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn port<C: Clock>(
+        alloc: &UsbBusAllocator<TestBus>,
+        clock: C,
+    ) -> HdlcPort<'_, TestBus, C, [u8; 32]> {
+        HdlcPort::new(
+            SerialPort::new(alloc),
+            clock,
+            Fcs::Crc16,
+            Some(Retransmit::new(100, 2)),
+            [0u8; 32],
+            [0u8; 32],
+        )
+    }
+
+    #[test]
+    fn loopback() {
+        let now = Cell::new(0u32);
+        let clock = || now.get();
+
+        let (alloc_a, host_a) = TestBus::new();
+        let (alloc_b, host_b) = TestBus::new();
+        let mut a = port(&alloc_a, clock);
+        let mut b = port(&alloc_b, clock);
+        let _dev_a = test_bus::device(&alloc_a);
+        let _dev_b = test_bus::device(&alloc_b);
+
+        // Delivery with escaped bytes and acknowledgement
+        a.send_frame(&[0x01, 0x7e, 0x7d, 0x02]).unwrap();
+        assert_eq!(a.send_frame(&[0x03]), Err(UsbError::WouldBlock));
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x01, 0x7e, 0x7d, 0x02]);
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+
+        // A lost frame is retransmitted after the timeout
+        a.send_frame(&[0x03]).unwrap();
+        assert!(transfer(&host_a, &host_b, true));
+        now.set(50);
+        a.poll().unwrap();
+        assert!(!transfer(&host_a, &host_b, false));
+        now.set(100);
+        a.poll().unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x03]);
+
+        // A lost acknowledgement causes a duplicate, which is dropped and acknowledged again
+        assert!(transfer(&host_b, &host_a, true));
+        now.set(200);
+        a.poll().unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame(), Err(UsbError::WouldBlock));
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+        assert_eq!(a.errors().retransmissions, 2);
+
+        // Corrupted frames are dropped
+        host_b.write(read_ep(), &[0x7e, 0x03, 0x01, 0x00, 0x00, 0x7e]);
+        assert_eq!(b.recv_frame(), Err(UsbError::WouldBlock));
+        assert_eq!(b.errors().fcs, 1);
+    }
+
+    #[test]
+    fn give_up() {
+        let now = Cell::new(0u32);
+        let clock = || now.get();
+
+        let (alloc_a, host_a) = TestBus::new();
+        let (alloc_b, host_b) = TestBus::new();
+        let mut a = port(&alloc_a, clock);
+        let mut b = port(&alloc_b, clock);
+        let _dev_a = test_bus::device(&alloc_a);
+        let _dev_b = test_bus::device(&alloc_b);
+
+        // The frame and both retransmissions are lost
+        a.send_frame(&[0x01]).unwrap();
+        for t in [100, 200, 300] {
+            assert!(transfer(&host_a, &host_b, true));
+            now.set(t);
+            a.poll().unwrap();
+        }
+
+        assert_eq!(a.errors().failed, 1);
+        assert_eq!(a.send_frame(&[0x02]), Err(UsbError::WouldBlock));
+
+        // The link is reset, and the reset frame is sent again if it's lost
+        assert!(transfer(&host_a, &host_b, true));
+        now.set(350);
+        a.poll().unwrap();
+        assert!(!transfer(&host_a, &host_b, false));
+        now.set(400);
+        a.poll().unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        b.poll().unwrap();
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+
+        // The peer accepts and acknowledges the next frames
+        a.send_frame(&[0x02]).unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x02]);
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+
+        a.send_frame(&[0x03]).unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x03]);
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+        assert_eq!(a.errors().failed, 1);
+    }
+
+    #[test]
+    fn ack_while_blocked() {
+        let now = Cell::new(0u32);
+        let clock = || now.get();
+
+        let (alloc_a, host_a) = TestBus::new();
+        let (alloc_b, host_b) = TestBus::new();
+        let mut a = port(&alloc_a, clock);
+        let mut b = port(&alloc_b, clock);
+        let _dev_a = test_bus::device(&alloc_a);
+        let _dev_b = test_bus::device(&alloc_b);
+
+        // B receives a frame but doesn't read it
+        a.send_frame(&[0x01]).unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        b.poll().unwrap();
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+
+        // A frame from A arrives ahead of the acknowledgement for a frame from B
+        a.send_frame(&[0x02]).unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        b.send_frame(&[0x10]).unwrap();
+        assert!(transfer(&host_b, &host_a, false));
+        assert_eq!(a.recv_frame().unwrap(), &[0x10]);
+        assert!(transfer(&host_a, &host_b, false));
+
+        b.poll().unwrap();
+        assert!(!b.is_sending());
+        assert!(!transfer(&host_b, &host_a, false));
+
+        // The dropped frame is received after a retransmission
+        assert_eq!(b.recv_frame().unwrap(), &[0x01]);
+        now.set(100);
+        a.poll().unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x02]);
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+        assert_eq!(b.errors(), HdlcErrors::default());
+    }
+
+    #[test]
+    fn give_up_after_lost_acks() {
+        let now = Cell::new(0u32);
+        let clock = || now.get();
+
+        let (alloc_a, host_a) = TestBus::new();
+        let (alloc_b, host_b) = TestBus::new();
+        let mut a = port(&alloc_a, clock);
+        let mut b = port(&alloc_b, clock);
+        let _dev_a = test_bus::device(&alloc_a);
+        let _dev_b = test_bus::device(&alloc_b);
+
+        // The frame is received, but the acknowledgements for it and both retransmissions are lost
+        a.send_frame(&[0x01]).unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x01]);
+        for t in [100, 200, 300] {
+            assert!(transfer(&host_b, &host_a, true));
+            now.set(t);
+            a.poll().unwrap();
+            assert!(transfer(&host_a, &host_b, false));
+            assert_eq!(b.recv_frame(), Err(UsbError::WouldBlock));
+        }
+
+        // The last frame was the reset frame, which the peer acknowledges
+        assert_eq!(a.errors().failed, 1);
+        assert!(a.is_sending());
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+
+        // The next frame isn't mistaken for a duplicate
+        a.send_frame(&[0x02]).unwrap();
+        assert!(transfer(&host_a, &host_b, false));
+        assert_eq!(b.recv_frame().unwrap(), &[0x02]);
+        assert!(transfer(&host_b, &host_a, false));
+        a.poll().unwrap();
+        assert!(!a.is_sending());
+        assert_eq!(a.errors().failed, 1);
+    }
+}
@@ -1,13 +1,41 @@
+//! `embedded-io` support and blocking I/O with timeouts.
+
 use super::SerialPort;
+use crate::clock::{self, Clock};
 use core::borrow::BorrowMut;
 use usb_device::bus::UsbBus;
 
+/// Error type of the `embedded-io` implementations and the blocking methods of [`SerialPort`]
 #[derive(Debug)]
-pub struct Error(usb_device::UsbError);
+#[non_exhaustive]
+pub enum Error {
+    /// An error from `usb-device`
+    Usb(usb_device::UsbError),
+
+    /// DTR is not set, so the host isn't connected. Only reported if enabled with
+    /// [`SerialPort::set_dtr_required`].
+    NotConnected,
+
+    /// A bus reset discarded data that was buffered in either direction. Reported once.
+    ConnectionReset,
+
+    /// The bus is suspended, so the operation can't make progress until it's resumed. Its kind is
+    /// `Other` rather than `Interrupted`, as callers usually retry interrupted operations right
+    /// away.
+    Suspended,
+
+    /// The operation didn't complete within the timeout
+    TimedOut,
+
+    /// Unsent data was discarded by an overwriting [`WritePolicy`](crate::WritePolicy) or by
+    /// [`SuspendWritePolicy::Drop`](crate::SuspendWritePolicy::Drop). Reported once by the next
+    /// write, which doesn't write anything.
+    Overrun,
+}
 
 impl From<usb_device::UsbError> for Error {
     fn from(e: usb_device::UsbError) -> Self {
-        Self(e)
+        Self::Usb(e)
     }
 }
 
@@ -21,12 +49,149 @@ impl core::error::Error for Error {}
 
 impl embedded_io::Error for Error {
     fn kind(&self) -> embedded_io::ErrorKind {
-        match self.0 {
-            usb_device::UsbError::Unsupported => embedded_io::ErrorKind::Unsupported,
-            usb_device::UsbError::BufferOverflow
-            | usb_device::UsbError::EndpointOverflow
-            | usb_device::UsbError::EndpointMemoryOverflow => embedded_io::ErrorKind::OutOfMemory,
-            _ => embedded_io::ErrorKind::Other,
+        match self {
+            Error::Usb(usb_device::UsbError::Unsupported) => embedded_io::ErrorKind::Unsupported,
+            Error::Usb(
+                usb_device::UsbError::BufferOverflow
+                | usb_device::UsbError::EndpointOverflow
+                | usb_device::UsbError::EndpointMemoryOverflow,
+            ) => embedded_io::ErrorKind::OutOfMemory,
+            Error::Usb(_) => embedded_io::ErrorKind::Other,
+            Error::NotConnected => embedded_io::ErrorKind::NotConnected,
+            Error::ConnectionReset => embedded_io::ErrorKind::ConnectionReset,
+            Error::Suspended => embedded_io::ErrorKind::Other,
+            Error::TimedOut => embedded_io::ErrorKind::TimedOut,
+            Error::Overrun => embedded_io::ErrorKind::Other,
+        }
+    }
+}
+
+impl<Bus: UsbBus, RS: BorrowMut<[u8]>, WS: BorrowMut<[u8]>> SerialPort<'_, Bus, RS, WS> {
+    /// Reads bytes into `data` and returns the number of bytes read, waiting until at least one
+    /// byte is available or `timeout_ms` has elapsed on `clock`.
+    ///
+    /// While waiting, `poll` is called repeatedly with the port so that it can call
+    /// `UsbDevice::poll`, which is needed for progress if nothing else polls the device.
+    ///
+    /// # Errors
+    ///
+    /// * [`TimedOut`](Error::TimedOut) - No bytes were received within the timeout.
+    /// * [`NotConnected`](Error::NotConnected), [`ConnectionReset`](Error::ConnectionReset) or
+    ///   [`Suspended`](Error::Suspended) - No bytes are available and none can be received.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn read_timeout(
+        &mut self,
+        data: &mut [u8],
+        timeout_ms: u32,
+        clock: &mut impl Clock,
+        mut poll: impl FnMut(&mut Self),
+    ) -> Result<usize, Error> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let start = clock.now_ms();
+
+        loop {
+            match self.read(data) {
+                Ok(0) | Err(usb_device::UsbError::WouldBlock) => self.check_blocked()?,
+                other => return Ok(other?),
+            }
+
+            if clock::elapsed_ms(clock, start) >= timeout_ms {
+                return Err(Error::TimedOut);
+            }
+
+            poll(self);
+        }
+    }
+
+    /// Writes all of `data` into the port, waiting for room in the write buffer until
+    /// `timeout_ms` has elapsed on `clock`. `poll` is called while waiting like in
+    /// [`read_timeout`](SerialPort::read_timeout).
+    ///
+    /// # Errors
+    ///
+    /// * [`TimedOut`](Error::TimedOut) - Not all of `data` could be written within the timeout.
+    ///   Part of it may have been written.
+    /// * [`NotConnected`](Error::NotConnected), [`ConnectionReset`](Error::ConnectionReset),
+    ///   [`Suspended`](Error::Suspended) or [`Overrun`](Error::Overrun) - As for
+    ///   `embedded_io::Write::write`.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn write_all_timeout(
+        &mut self,
+        mut data: &[u8],
+        timeout_ms: u32,
+        clock: &mut impl Clock,
+        mut poll: impl FnMut(&mut Self),
+    ) -> Result<(), Error> {
+        let start = clock.now_ms();
+
+        if !data.is_empty() {
+            self.check_write()?;
+        }
+
+        while !data.is_empty() {
+            match self.write_checked(data)? {
+                0 => self.check_blocked()?,
+                count => data = &data[count..],
+            }
+
+            if data.is_empty() {
+                break;
+            }
+
+            if clock::elapsed_ms(clock, start) >= timeout_ms {
+                return Err(Error::TimedOut);
+            }
+
+            poll(self);
+        }
+
+        Ok(())
+    }
+
+    /// Reports errors that must be seen before anything new is written.
+    fn check_write(&mut self) -> Result<(), Error> {
+        if core::mem::take(&mut self.connection_reset) {
+            Err(Error::ConnectionReset)
+        } else if self.take_data_lost() {
+            Err(Error::Overrun)
+        } else if self.dtr_required() && !self.dtr() {
+            Err(Error::NotConnected)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reports why a read or write that can't make progress shouldn't keep waiting, if it
+    /// shouldn't.
+    fn check_blocked(&mut self) -> Result<(), Error> {
+        if core::mem::take(&mut self.connection_reset) {
+            Err(Error::ConnectionReset)
+        } else if self.dtr_required() && !self.dtr() {
+            Err(Error::NotConnected)
+        } else if self.is_suspended() {
+            Err(Error::Suspended)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `true` if a read or write that can't make progress would return an error instead of
+    /// waiting.
+    fn io_error_pending(&self) -> bool {
+        self.connection_reset || (self.dtr_required() && !self.dtr()) || self.is_suspended()
+    }
+
+    /// Like `write`, but returns 0 instead of `WouldBlock`.
+    fn write_checked(&mut self, data: &[u8]) -> Result<usize, Error> {
+        match self.write(data) {
+            Err(usb_device::UsbError::WouldBlock) => Ok(0),
+            Err(usb_device::UsbError::InvalidState) if self.is_suspended() => Err(Error::Suspended),
+            other => Ok(other?),
         }
     }
 }
@@ -42,23 +207,43 @@ impl<Bus: UsbBus, RS: BorrowMut<[u8]>, WS: BorrowMut<[u8]>> embedded_io::Read
 {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         loop {
-            match self.read(buf).map_err(From::from) {
+            match self.read(buf) {
                 // We are required by `embedded-io` to continue reading until at least one byte is
                 // read.
-                Ok(0) => {}
-                Err(usb_device::UsbError::WouldBlock) => {}
+                Ok(0) | Err(usb_device::UsbError::WouldBlock) => self.check_blocked()?,
                 other => return Ok(other?),
             }
         }
     }
 }
 
+impl<Bus: UsbBus, RS: BorrowMut<[u8]>, WS: BorrowMut<[u8]>> embedded_io::BufRead
+    for SerialPort<'_, Bus, RS, WS>
+{
+    fn fill_buf(&mut self) -> Result<&[u8], Self::Error> {
+        // We are required by `embedded-io` to block until at least one byte is available.
+        while self.read_buf.available_read() == 0 {
+            self.poll()?;
+
+            if self.read_buf.available_read() == 0 {
+                self.check_blocked()?;
+            }
+        }
+
+        Ok(self.read_buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consume(amt);
+    }
+}
+
 impl<Bus: UsbBus, RS: BorrowMut<[u8]>, WS: BorrowMut<[u8]>> embedded_io::ReadReady
     for SerialPort<'_, Bus, RS, WS>
 {
     fn read_ready(&mut self) -> Result<bool, Self::Error> {
         self.poll()?;
-        Ok(self.read_buf.available_read() != 0)
+        Ok(self.read_buf.available_read() != 0 || self.io_error_pending())
     }
 }
 
@@ -70,13 +255,14 @@ impl<Bus: UsbBus, RS: BorrowMut<[u8]>, WS: BorrowMut<[u8]>> embedded_io::Write
             return Ok(0);
         }
 
+        self.check_write()?;
+
         loop {
-            match self.write(buf) {
+            match self.write_checked(buf)? {
                 // We are required by `embedded-io` to continue writing until at least one byte is
                 // written.
-                Ok(0) => {}
-                Err(usb_device::UsbError::WouldBlock) => {}
-                other => return Ok(other?),
+                0 => self.check_blocked()?,
+                count => return Ok(count),
             }
         }
     }
@@ -90,6 +276,106 @@ impl<Bus: UsbBus, RS: BorrowMut<[u8]>, WS: BorrowMut<[u8]>> embedded_io::WriteRe
     for SerialPort<'_, Bus, RS, WS>
 {
     fn write_ready(&mut self) -> Result<bool, Self::Error> {
-        Ok(self.write_buf.available_write() != 0)
+        Ok(self.write_policy() != crate::WritePolicy::Block
+            || self.write_buf.available_write() != 0
+            || self.io_error_pending())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::{self, TestBus, read_ep, write_ep};
+    use core::cell::Cell;
+
+    #[test]
+    fn timeouts() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 64], [0u8; 4]);
+        let _dev = test_bus::device(&alloc);
+
+        let now = Cell::new(0u32);
+        let mut clock = || now.get();
+        let mut buf = [0u8; 8];
+
+        let poll = |_: &mut SerialPort<_, _, _>| now.set(now.get() + 10);
+        assert!(matches!(
+            serial.read_timeout(&mut buf, 100, &mut clock, poll),
+            Err(Error::TimedOut)
+        ));
+        assert_eq!(now.get(), 100);
+
+        let poll = |_: &mut SerialPort<_, _, _>| host.write(read_ep(), b"ab");
+        assert_eq!(
+            serial
+                .read_timeout(&mut buf, 100, &mut clock, poll)
+                .unwrap(),
+            2
+        );
+
+        let poll = |_: &mut SerialPort<_, _, _>| {
+            now.set(now.get() + 10);
+            host.read(write_ep());
+        };
+        serial
+            .write_all_timeout(b"0123456789", 100, &mut clock, poll)
+            .unwrap();
+
+        let poll = |_: &mut SerialPort<_, _, _>| now.set(now.get() + 10);
+        assert!(matches!(
+            serial.write_all_timeout(b"0123456789", 100, &mut clock, poll),
+            Err(Error::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        use embedded_io::{Error as _, ErrorKind, Read, Write};
+        use usb_device::class::UsbClass;
+        use usb_device::device::UsbDeviceState;
+
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+        let mut buf = [0u8; 8];
+
+        // The first packet is sent, the second one is still buffered.
+        assert_eq!(Write::write(&mut serial, b"ab").unwrap(), 2);
+        assert_eq!(Write::write(&mut serial, b"cd").unwrap(), 2);
+        serial.reset();
+        let err = Read::read(&mut serial, &mut buf).unwrap_err();
+        assert!(matches!(err, Error::ConnectionReset));
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        host.read(write_ep());
+
+        // An empty reset isn't reported.
+        serial.reset();
+        assert_eq!(Write::write(&mut serial, b"ab").unwrap(), 2);
+
+        serial.set_device_state(UsbDeviceState::Suspend, false);
+        let err = Read::read(&mut serial, &mut buf).unwrap_err();
+        assert!(matches!(err, Error::Suspended));
+        assert_eq!(err.kind(), ErrorKind::Other);
+
+        serial.set_suspend_write_policy(crate::SuspendWritePolicy::Error);
+        assert!(matches!(
+            Write::write(&mut serial, b"ab"),
+            Err(Error::Suspended)
+        ));
+
+        serial.set_suspend_write_policy(crate::SuspendWritePolicy::Drop);
+        assert_eq!(Write::write(&mut serial, b"ab").unwrap(), 2);
+        serial.set_device_state(UsbDeviceState::Configured, false);
+        let err = Write::write(&mut serial, b"ab").unwrap_err();
+        assert!(matches!(err, Error::Overrun));
+        assert_eq!(Write::write(&mut serial, b"ab").unwrap(), 2);
+
+        serial.set_dtr_required(true);
+        let err = Write::write(&mut serial, b"ab").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert!(matches!(
+            Read::read(&mut serial, &mut buf),
+            Err(Error::NotConnected)
+        ));
     }
 }
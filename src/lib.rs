@@ -55,13 +55,36 @@
 
 #![no_std]
 
+mod bridge;
 mod buffer;
 mod cdc_acm;
-mod io;
+mod clock;
+mod console;
+mod framing;
+mod hdlc;
+pub mod io;
+mod modem;
+mod ms_os;
 mod serial_port;
+mod stats;
+#[cfg(test)]
+mod test_bus;
+mod transfer;
+mod wrapper;
 
+pub use crate::bridge::*;
 pub use crate::buffer::DefaultBufferStore;
 pub use crate::cdc_acm::*;
+pub use crate::clock::Clock;
+pub use crate::console::{Console, ConsoleMode};
+pub use crate::framing::{FrameEncoding, FrameErrors, FramedPort};
+pub use crate::hdlc::{Fcs, HdlcErrors, HdlcPort, Retransmit};
+pub use crate::modem::{
+    AtCommand, AtCommandKind, AtHandler, AtModem, AtResponse, AtResult, ModemMode,
+};
+pub use crate::ms_os::*;
 pub use crate::serial_port::*;
+pub use crate::stats::SerialStats;
+pub use crate::transfer::TransferPort;
 pub use embedded_io;
 pub use usb_device::{Result, UsbError};
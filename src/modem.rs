@@ -0,0 +1,846 @@
+use crate::buffer::DefaultBufferStore;
+use crate::cdc_acm::CommProtocol;
+use crate::clock::{self, Clock};
+use crate::serial_port::SerialPort;
+use crate::wrapper::forward_usb_class;
+use core::borrow::BorrowMut;
+use usb_device::Result;
+use usb_device::class_prelude::*;
+
+const ESCAPE_CHAR: u8 = b'+';
+const BACKSPACE: u8 = 0x08;
+const DEL: u8 = 0x7f;
+const DEFAULT_GUARD_TIME_MS: u32 = 1000;
+
+const RESULT_OK: &[u8] = b"OK";
+const RESULT_CONNECT: &[u8] = b"CONNECT";
+const RESULT_NO_CARRIER: &[u8] = b"NO CARRIER";
+const RESULT_ERROR: &[u8] = b"ERROR";
+
+/// Operating mode of an [`AtModem`]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum ModemMode {
+    /// Received lines are parsed as AT commands.
+    #[default]
+    Command,
+
+    /// Data is passed through, except for the `+++` escape sequence.
+    Data,
+}
+
+/// Syntax variant of an AT command
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AtCommandKind<'l> {
+    /// `AT+CMD` or `ATX`
+    Execute,
+
+    /// `AT+CMD?` or `ATS0?`
+    Read,
+
+    /// `AT+CMD=?`
+    Test,
+
+    /// `AT+CMD=<args>`, `ATX<n>`, `ATS0=<n>` or `ATD<dial string>`. The arguments are passed
+    /// unparsed.
+    Set(&'l str),
+}
+
+/// A single AT command from a command line
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AtCommand<'l> {
+    name: &'l str,
+    kind: AtCommandKind<'l>,
+}
+
+impl<'l> AtCommand<'l> {
+    /// Gets the name of the command as received, such as `+CSQ`, `&F`, `S0` or `D`.
+    pub fn name(&self) -> &'l str {
+        self.name
+    }
+
+    /// Gets the syntax variant and arguments of the command.
+    pub fn kind(&self) -> AtCommandKind<'l> {
+        self.kind
+    }
+
+    /// Parses the first command of a command line without the `AT` prefix, and returns it with the
+    /// rest of the line. Returns `None` on a syntax error.
+    fn parse(line: &'l str) -> Option<(Self, &'l str)> {
+        let bytes = line.as_bytes();
+
+        let name_len = match bytes.first()?.to_ascii_uppercase() {
+            b'+' => {
+                let len = bytes[1..]
+                    .iter()
+                    .take_while(|&&b| b.is_ascii_alphanumeric() || b"!%-./:_".contains(&b))
+                    .count();
+
+                if len == 0 {
+                    return None;
+                }
+
+                let (name, rest) = line.split_at(1 + len);
+                let (kind, rest) = if let Some(rest) = rest.strip_prefix("=?") {
+                    (AtCommandKind::Test, rest)
+                } else if let Some(rest) = rest.strip_prefix('?') {
+                    (AtCommandKind::Read, rest)
+                } else if let Some(rest) = rest.strip_prefix('=') {
+                    let mut quoted = false;
+                    let end = rest
+                        .bytes()
+                        .position(|b| {
+                            quoted ^= b == b'"';
+                            b == b';' && !quoted
+                        })
+                        .unwrap_or(rest.len());
+
+                    (AtCommandKind::Set(&rest[..end]), &rest[end..])
+                } else {
+                    (AtCommandKind::Execute, rest)
+                };
+
+                let rest = match rest.strip_prefix(';') {
+                    Some(rest) => rest,
+                    None if rest.is_empty() => rest,
+                    None => return None,
+                };
+
+                return Some((AtCommand { name, kind }, rest));
+            }
+            b'D' => {
+                let (name, rest) = line.split_at(1);
+                let command = AtCommand {
+                    name,
+                    kind: AtCommandKind::Set(rest),
+                };
+
+                return Some((command, ""));
+            }
+            b'&' if bytes.get(1).is_some_and(u8::is_ascii_alphabetic) => 2,
+            b'S' => {
+                let len = bytes[1..].iter().take_while(|b| b.is_ascii_digit()).count();
+
+                if len == 0 {
+                    return None;
+                }
+
+                1 + len
+            }
+            b'A'..=b'Z' => 1,
+            _ => return None,
+        };
+
+        let (name, rest) = line.split_at(name_len);
+        let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+
+        let (kind, rest) = if let Some(rest) = rest.strip_prefix('?') {
+            (AtCommandKind::Read, rest)
+        } else if let Some(rest) = rest.strip_prefix('=') {
+            let (args, rest) = rest.split_at(digits(rest));
+            (AtCommandKind::Set(args), rest)
+        } else {
+            match rest.split_at(digits(rest)) {
+                ("", rest) => (AtCommandKind::Execute, rest),
+                (args, rest) => (AtCommandKind::Set(args), rest),
+            }
+        };
+
+        Some((AtCommand { name, kind }, rest))
+    }
+}
+
+/// Final result of an AT command handler
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AtResult {
+    /// The command succeeded. The rest of the command line is executed, and `OK` is sent after the
+    /// last command.
+    Ok,
+
+    /// The command failed. The rest of the command line is skipped and `ERROR` is sent.
+    Error,
+
+    /// A connection was established. The rest of the command line is skipped, `CONNECT` is sent and
+    /// the modem switches to data mode.
+    Connect,
+}
+
+/// Handler for an AT command, which gets the application context, the command and a writer for
+/// information responses.
+pub type AtHandler<T> = fn(&mut T, &AtCommand, &mut AtResponse) -> AtResult;
+
+trait Sink {
+    fn write_parts(&mut self, parts: &[&[u8]]) -> bool;
+}
+
+impl<B, RS, WS> Sink for SerialPort<'_, B, RS, WS>
+where
+    B: UsbBus,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    fn write_parts(&mut self, parts: &[&[u8]]) -> bool {
+        SerialPort::write_parts(self, parts).is_ok()
+    }
+}
+
+/// Writer for information responses of an AT command handler
+pub struct AtResponse<'r> {
+    sink: &'r mut dyn Sink,
+}
+
+impl AtResponse<'_> {
+    /// Writes an information response line such as `+CSQ: 20,99`, which is framed by CR LF.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There is not enough room in the write
+    ///   buffer for the whole line.
+    pub fn line(&mut self, text: &[u8]) -> Result<()> {
+        match self.sink.write_parts(&[b"\r\n", text, b"\r\n"]) {
+            true => Ok(()),
+            false => Err(UsbError::WouldBlock),
+        }
+    }
+}
+
+/// AT command interpreter and modem emulation over a [`SerialPort`].
+///
+/// The communication interface reports the V.25ter protocol (`0x01`), so hosts treat the port as a
+/// modem. In command mode, lines starting with `AT` and ending with CR are parsed into basic
+/// (`ATE0`, `ATS0=1`, `ATD123`) and extended (`AT+CSQ`, `AT+CGDCONT=1,"IP"`) commands, which may be
+/// concatenated on one line with extended commands separated by `;`. Each command is dispatched to
+/// the handler registered for its name, compared case-insensitively. `E` (echo) and `O` (return to
+/// data mode) are handled internally unless a handler is registered for them. Unknown commands
+/// result in `ERROR`.
+///
+/// In data mode, [`read`](AtModem::read) and [`write`](AtModem::write) pass data through. The
+/// `+++` escape sequence switches back to command mode if it's preceded and followed by at least
+/// the guard time without other data, and its characters are at most the guard time apart.
+/// Otherwise the `+` characters are passed through as data. Input is timestamped when it's read,
+/// so `read` and [`poll`](AtModem::poll) should be called regularly in data mode.
+///
+/// After a bus reset the modem is back in command mode with no result code pending, which only
+/// works if `UsbDevice::poll` is given the modem. Call [`poll`](AtModem::poll) after each
+/// `UsbDevice::poll`.
+pub struct AtModem<'a, 'h, B, T, C, L, RS = DefaultBufferStore, WS = DefaultBufferStore>
+where
+    B: UsbBus,
+    C: Clock,
+    L: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    port: SerialPort<'a, B, RS, WS>,
+    clock: C,
+    context: T,
+    handlers: &'h [(&'h str, AtHandler<T>)],
+    mode: ModemMode,
+    echo: bool,
+    guard_time_ms: u32,
+    line: L,
+    line_len: usize,
+    line_overflow: bool,
+    pending_result: Option<&'static [u8]>,
+    last_rx: u32,
+    escape_count: u8,
+    escape_release: u8,
+}
+
+impl<'a, 'h, B, T, C, L, RS, WS> AtModem<'a, 'h, B, T, C, L, RS, WS>
+where
+    B: UsbBus,
+    C: Clock,
+    L: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+    WS: BorrowMut<[u8]>,
+{
+    /// Creates a new modem in command mode with echo enabled and a guard time of one second. The
+    /// length of `line` limits the length of command lines. The port must not have been enumerated
+    /// yet, because the protocol in its interface descriptors is changed.
+    pub fn new(
+        mut port: SerialPort<'a, B, RS, WS>,
+        clock: C,
+        line: L,
+        context: T,
+        handlers: &'h [(&'h str, AtHandler<T>)],
+    ) -> Self {
+        port.set_comm_protocol(CommProtocol::V25ter);
+
+        AtModem {
+            port,
+            clock,
+            context,
+            handlers,
+            mode: ModemMode::Command,
+            echo: true,
+            guard_time_ms: DEFAULT_GUARD_TIME_MS,
+            line,
+            line_len: 0,
+            line_overflow: false,
+            pending_result: None,
+            last_rx: 0,
+            escape_count: 0,
+            escape_release: 0,
+        }
+    }
+
+    /// Gets the serial port.
+    pub fn port(&self) -> &SerialPort<'a, B, RS, WS> {
+        &self.port
+    }
+
+    /// Mutably gets the serial port. Reading from or writing to the port directly bypasses the
+    /// command interpreter.
+    pub fn port_mut(&mut self) -> &mut SerialPort<'a, B, RS, WS> {
+        &mut self.port
+    }
+
+    /// Gets the application context passed to the handlers.
+    pub fn context(&self) -> &T {
+        &self.context
+    }
+
+    /// Mutably gets the application context passed to the handlers.
+    pub fn context_mut(&mut self) -> &mut T {
+        &mut self.context
+    }
+
+    /// Destroys the modem and returns the serial port, clock, line buffer and context.
+    pub fn release(self) -> (SerialPort<'a, B, RS, WS>, C, L, T) {
+        (self.port, self.clock, self.line, self.context)
+    }
+
+    /// Gets the current mode.
+    pub fn mode(&self) -> ModemMode {
+        self.mode
+    }
+
+    /// Returns `true` if command mode input is echoed, as set with `ATE`.
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// Gets the escape sequence guard time in milliseconds.
+    pub fn guard_time_ms(&self) -> u32 {
+        self.guard_time_ms
+    }
+
+    /// Sets the escape sequence guard time in milliseconds. The default is 1000 ms.
+    pub fn set_guard_time_ms(&mut self, guard_time_ms: u32) {
+        self.guard_time_ms = guard_time_ms;
+    }
+
+    /// Processes command mode input and executes complete command lines, or detects the end of the
+    /// escape sequence in data mode.
+    pub fn poll(&mut self) -> Result<()> {
+        if !self.write_pending_result() {
+            return self.flush();
+        }
+
+        match self.mode {
+            ModemMode::Command => self.process_commands()?,
+            ModemMode::Data => {
+                self.port.poll()?;
+
+                if self.escape_count > 0
+                    && self.escape_release == 0
+                    && self.port.read_buf.available_read() == 0
+                    && clock::elapsed_ms(&mut self.clock, self.last_rx) >= self.guard_time_ms
+                {
+                    if self.escape_count == 3 {
+                        self.enter_command_mode(RESULT_OK);
+                    } else {
+                        // An incomplete escape sequence is passed through by the next read.
+                        self.escape_release = self.escape_count;
+                        self.escape_count = 0;
+                    }
+                }
+            }
+        }
+
+        self.flush()
+    }
+
+    /// Reads data in data mode into `data` and returns the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes available for reading.
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The modem is in command mode.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        if self.mode != ModemMode::Data {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.port.poll()?;
+
+        let now = self.clock.now_ms();
+        let mut count = 0;
+
+        while count < data.len() {
+            if self.escape_release > 0 {
+                // Pass through escape characters that turned out not to be an escape sequence.
+                data[count] = ESCAPE_CHAR;
+                count += 1;
+                self.escape_release -= 1;
+                continue;
+            }
+
+            let Some(&b) = self.port.read_buf.data().first() else {
+                break;
+            };
+
+            let quiet = now.wrapping_sub(self.last_rx) >= self.guard_time_ms;
+
+            if b == ESCAPE_CHAR
+                && match self.escape_count {
+                    0 => quiet,
+                    1 | 2 => !quiet,
+                    _ => false,
+                }
+            {
+                self.escape_count += 1;
+                self.last_rx = now;
+                self.port.read_buf.discard(1);
+                continue;
+            }
+
+            if self.escape_count > 0 {
+                self.escape_release = self.escape_count;
+                self.escape_count = 0;
+                continue;
+            }
+
+            data[count] = b;
+            count += 1;
+            self.last_rx = now;
+            self.port.read_buf.discard(1);
+        }
+
+        if count == 0 {
+            Err(UsbError::WouldBlock)
+        } else {
+            Ok(count)
+        }
+    }
+
+    /// Writes data in data mode and returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes could be written because the
+    ///   buffers are full, or the `CONNECT` result code couldn't be written yet.
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The modem is in command mode.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if self.mode != ModemMode::Data {
+            return Err(UsbError::InvalidState);
+        }
+
+        // Data must not reach the host before the CONNECT result code.
+        if !self.write_pending_result() {
+            self.flush()?;
+            return Err(UsbError::WouldBlock);
+        }
+
+        self.port.write(data)
+    }
+
+    /// Ends the connection by switching to command mode and sending `NO CARRIER`. Does nothing in
+    /// command mode.
+    pub fn hang_up(&mut self) -> Result<()> {
+        if self.mode == ModemMode::Data {
+            self.enter_command_mode(RESULT_NO_CARRIER);
+        }
+
+        self.flush()
+    }
+
+    /// Sends an unsolicited result code such as `RING` in command mode, framed by CR LF.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There is not enough room in the write
+    ///   buffer for the whole result code.
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The modem is in data mode.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn send_unsolicited(&mut self, text: &[u8]) -> Result<()> {
+        if self.mode != ModemMode::Command {
+            return Err(UsbError::InvalidState);
+        }
+
+        if !write_result(&mut self.port, text) {
+            return Err(UsbError::WouldBlock);
+        }
+
+        self.flush()
+    }
+
+    /// Writes the result code that didn't fit into the write buffer earlier, if any. Returns `false`
+    /// if there is still not enough room.
+    fn write_pending_result(&mut self) -> bool {
+        if let Some(result) = self.pending_result {
+            if !write_result(&mut self.port, result) {
+                return false;
+            }
+
+            self.pending_result = None;
+        }
+
+        true
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self.port.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn process_commands(&mut self) -> Result<()> {
+        while self.mode == ModemMode::Command && self.pending_result.is_none() {
+            if self.port.read_buf.available_read() == 0 {
+                self.port.poll()?;
+            }
+
+            let Some(&b) = self.port.read_buf.data().first() else {
+                break;
+            };
+
+            if self.echo && self.port.write_parts(&[&[b]]).is_err() {
+                // No room for the echo, so leave the byte for later.
+                break;
+            }
+
+            self.port.read_buf.discard(1);
+
+            match b {
+                b'\r' => {
+                    self.execute_line();
+                    self.line_len = 0;
+                    self.line_overflow = false;
+                }
+                b'\n' => {}
+                BACKSPACE | DEL => self.line_len = self.line_len.saturating_sub(1),
+                b => {
+                    let line = self.line.borrow_mut();
+
+                    if self.line_len < line.len() {
+                        line[self.line_len] = b;
+                        self.line_len += 1;
+                    } else {
+                        self.line_overflow = true;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute_line(&mut self) {
+        let line = &self.line.borrow()[..self.line_len];
+
+        let Some(commands) = line
+            .get(..2)
+            .filter(|prefix| prefix.eq_ignore_ascii_case(b"AT"))
+            .map(|_| &line[2..])
+        else {
+            // Not a command line.
+            return;
+        };
+
+        let result = match core::str::from_utf8(commands) {
+            Ok(_) if self.line_overflow => AtResult::Error,
+            Ok(mut commands) => {
+                let mut result = AtResult::Ok;
+
+                while !commands.is_empty() && result == AtResult::Ok {
+                    let Some((command, rest)) = AtCommand::parse(commands) else {
+                        result = AtResult::Error;
+                        break;
+                    };
+
+                    commands = rest;
+                    result = match self
+                        .handlers
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(command.name))
+                    {
+                        Some((_, handler)) => {
+                            let mut response = AtResponse {
+                                sink: &mut self.port,
+                            };
+
+                            handler(&mut self.context, &command, &mut response)
+                        }
+                        None => builtin(&mut self.echo, &command),
+                    };
+                }
+
+                result
+            }
+            Err(_) => AtResult::Error,
+        };
+
+        let code = match result {
+            AtResult::Ok => RESULT_OK,
+            AtResult::Error => RESULT_ERROR,
+            AtResult::Connect => {
+                self.mode = ModemMode::Data;
+                self.last_rx = self.clock.now_ms();
+                self.escape_count = 0;
+                self.escape_release = 0;
+                RESULT_CONNECT
+            }
+        };
+
+        if !write_result(&mut self.port, code) {
+            self.pending_result = Some(code);
+        }
+    }
+
+    fn enter_command_mode(&mut self, code: &'static [u8]) {
+        self.mode = ModemMode::Command;
+        self.escape_count = 0;
+        self.escape_release = 0;
+        self.line_len = 0;
+        self.line_overflow = false;
+
+        if !write_result(&mut self.port, code) {
+            self.pending_result = Some(code);
+        }
+    }
+}
+
+/// Executes the commands that are handled internally.
+fn builtin(echo: &mut bool, command: &AtCommand) -> AtResult {
+    let is = |name: &str| command.name.eq_ignore_ascii_case(name);
+
+    match command.kind {
+        AtCommandKind::Execute | AtCommandKind::Set("0") if is("E") => *echo = false,
+        AtCommandKind::Set("1") if is("E") => *echo = true,
+        AtCommandKind::Execute | AtCommandKind::Set("0") if is("O") => return AtResult::Connect,
+        _ => return AtResult::Error,
+    }
+
+    AtResult::Ok
+}
+
+/// Writes a result code framed by CR LF. Returns `false` if there was not enough room.
+fn write_result(sink: &mut dyn Sink, code: &[u8]) -> bool {
+    sink.write_parts(&[b"\r\n", code, b"\r\n"])
+}
+
+forward_usb_class! {
+    impl<B, T, C, L, RS, WS> UsbClass<B> for AtModem<'_, '_, B, T, C, L, RS, WS>
+    where
+        B: UsbBus,
+        C: Clock,
+        L: BorrowMut<[u8]>,
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        self.port;
+
+        fn reset(&mut self) {
+            self.mode = ModemMode::Command;
+            self.line_len = 0;
+            self.line_overflow = false;
+            self.pending_result = None;
+            self.escape_count = 0;
+            self.escape_release = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_bus::{self, TestBus, TestHost, read_ep, write_ep};
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    fn parse_all(mut line: &str) -> Option<Vec<AtCommand<'_>>> {
+        let mut commands = Vec::new();
+
+        while !line.is_empty() {
+            let (command, rest) = AtCommand::parse(line)?;
+            commands.push(command);
+            line = rest;
+        }
+
+        Some(commands)
+    }
+
+    fn command<'l>(name: &'l str, kind: AtCommandKind<'l>) -> AtCommand<'l> {
+        AtCommand { name, kind }
+    }
+
+    #[test]
+    fn parse() {
+        use AtCommandKind::*;
+
+        assert_eq!(
+            parse_all("E0VS0=1S7?&F+CSQ;+CGDCONT=1,\"I;P\";+CGDCONT=?;+CREG?;D*99#").unwrap(),
+            [
+                command("E", Set("0")),
+                command("V", Execute),
+                command("S0", Set("1")),
+                command("S7", Read),
+                command("&F", Execute),
+                command("+CSQ", Execute),
+                command("+CGDCONT", Set("1,\"I;P\"")),
+                command("+CGDCONT", Test),
+                command("+CREG", Read),
+                command("D", Set("*99#")),
+            ]
+        );
+
+        assert_eq!(parse_all("+"), None);
+        assert_eq!(parse_all("+CSQ+CREG"), None);
+        assert_eq!(parse_all("#"), None);
+    }
+
+    fn output<B, T, C, L, RS, WS>(
+        modem: &mut AtModem<B, T, C, L, RS, WS>,
+        host: &TestHost,
+    ) -> Vec<u8>
+    where
+        B: UsbBus,
+        C: Clock,
+        L: BorrowMut<[u8]>,
+        RS: BorrowMut<[u8]>,
+        WS: BorrowMut<[u8]>,
+    {
+        let mut data = Vec::new();
+
+        loop {
+            modem.port_mut().flush().ok();
+
+            match host.read(write_ep()) {
+                Some(packet) => data.extend_from_slice(&packet),
+                None => return data,
+            }
+        }
+    }
+
+    fn csq(calls: &mut u32, _: &AtCommand, response: &mut AtResponse) -> AtResult {
+        *calls += 1;
+        response.line(b"+CSQ: 20,99").unwrap();
+        AtResult::Ok
+    }
+
+    fn dial(_: &mut u32, command: &AtCommand, _: &mut AtResponse) -> AtResult {
+        match command.kind() {
+            AtCommandKind::Set("*99#") => AtResult::Connect,
+            _ => AtResult::Error,
+        }
+    }
+
+    #[test]
+    fn modem() {
+        let now = Cell::new(0u32);
+        let handlers: &[(&str, AtHandler<u32>)] = &[("+CSQ", csq), ("D", dial)];
+
+        let (alloc, host) = TestBus::new();
+        let mut modem = AtModem::new(
+            SerialPort::new(&alloc),
+            || now.get(),
+            [0u8; 32],
+            0,
+            handlers,
+        );
+        let _dev = test_bus::device(&alloc);
+
+        host.write(read_ep(), b"AT+csq\r\nATE0+CSQ;+CREG?\r\nATD123\r");
+        modem.poll().unwrap();
+        assert_eq!(
+            output(&mut modem, &host),
+            b"AT+csq\r\r\n+CSQ: 20,99\r\n\r\nOK\r\n\nATE0+CSQ;+CREG?\r\r\n+CSQ: 20,99\r\n\r\nERROR\r\n\r\nERROR\r\n"
+        );
+        assert_eq!(*modem.context(), 2);
+
+        host.write(read_ep(), b"ATD*99#\r");
+        modem.poll().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Data);
+        assert_eq!(output(&mut modem, &host), b"\r\nCONNECT\r\n");
+
+        // Escape characters without a guard time before them are data
+        let mut buf = [0u8; 16];
+        host.write(read_ep(), b"ab+++");
+        assert_eq!(modem.read(&mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"ab+++");
+
+        // Escape sequence interrupted by data
+        now.set(1000);
+        host.write(read_ep(), b"++x");
+        assert_eq!(modem.read(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"++x");
+
+        // Incomplete escape sequence followed by the guard time
+        now.set(2000);
+        host.write(read_ep(), b"++");
+        assert_eq!(modem.read(&mut buf), Err(UsbError::WouldBlock));
+        now.set(2999);
+        modem.poll().unwrap();
+        assert_eq!(modem.read(&mut buf), Err(UsbError::WouldBlock));
+        now.set(3000);
+        modem.poll().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Data);
+        assert_eq!(modem.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"++");
+
+        // Complete escape sequence
+        now.set(4000);
+        host.write(read_ep(), b"+++");
+        assert_eq!(modem.read(&mut buf), Err(UsbError::WouldBlock));
+        now.set(4999);
+        modem.poll().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Data);
+        now.set(5000);
+        modem.poll().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Command);
+        assert_eq!(output(&mut modem, &host), b"\r\nOK\r\n");
+
+        host.write(read_ep(), b"ATO\r");
+        modem.poll().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Data);
+        modem.hang_up().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Command);
+        assert_eq!(
+            output(&mut modem, &host),
+            b"\r\nCONNECT\r\n\r\nNO CARRIER\r\n"
+        );
+    }
+
+    #[test]
+    fn connect_before_data() {
+        let handlers: &[(&str, AtHandler<u32>)] = &[("D", dial)];
+
+        let (alloc, host) = TestBus::new();
+        let mut modem = AtModem::new(
+            SerialPort::new_with_store(&alloc, [0u8; 64], [0u8; 16]),
+            || 0,
+            [0u8; 32],
+            0,
+            handlers,
+        );
+        let _dev = test_bus::device(&alloc);
+
+        // The echo leaves no room for the result code, which has to wait
+        host.write(read_ep(), b"ATD*99#\r");
+        modem.poll().unwrap();
+        assert_eq!(modem.mode(), ModemMode::Data);
+
+        // Data written in the meantime follows the result code
+        assert_eq!(modem.write(b"data"), Ok(4));
+        assert_eq!(output(&mut modem, &host), b"ATD*99#\r\r\nCONNECT\r\ndata");
+    }
+}
@@ -0,0 +1,405 @@
+use usb_device::{Result, UsbError};
+
+/// Platform capability UUID for MS OS 2.0 descriptors ({D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}).
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+/// Minimum Windows version the descriptor set applies to (Windows 8.1).
+const WINDOWS_VERSION_8_1: u32 = 0x0603_0000;
+
+/// wIndex value of the vendor request used to retrieve the descriptor set.
+pub(crate) const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+
+/// Size of the control transfer buffer of `usb-device`, which limits the size of the descriptor
+/// set.
+#[cfg(not(feature = "control-buffer-256"))]
+pub(crate) const CONTROL_BUFFER_LEN: usize = 128;
+#[cfg(feature = "control-buffer-256")]
+pub(crate) const CONTROL_BUFFER_LEN: usize = 256;
+
+const MS_OS_20_SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const MS_OS_20_SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const MS_OS_20_SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const MS_OS_20_FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const MS_OS_20_FEATURE_REG_PROPERTY: u16 = 0x04;
+
+const SET_HEADER_LEN: usize = 10;
+const CONFIGURATION_SUBSET_HEADER_LEN: usize = 8;
+const FUNCTION_SUBSET_HEADER_LEN: usize = 8;
+const COMPATIBLE_ID_LEN: usize = 20;
+
+const REG_SZ: u16 = 1;
+const REG_EXPAND_SZ: u16 = 2;
+const REG_BINARY: u16 = 3;
+const REG_DWORD_LITTLE_ENDIAN: u16 = 4;
+const REG_MULTI_SZ: u16 = 7;
+
+/// Compatible ID feature descriptor contents
+///
+/// Windows uses the compatible ID to pick a driver for the function without requiring an INF file.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CompatibleId {
+    compatible_id: [u8; 8],
+    sub_compatible_id: [u8; 8],
+}
+
+impl CompatibleId {
+    /// Binds the function to the WinUSB driver.
+    pub const WINUSB: CompatibleId = CompatibleId::new(*b"WINUSB\0\0", [0; 8]);
+
+    /// Creates a compatible ID from raw, zero-padded ASCII identifiers.
+    pub const fn new(compatible_id: [u8; 8], sub_compatible_id: [u8; 8]) -> Self {
+        CompatibleId {
+            compatible_id,
+            sub_compatible_id,
+        }
+    }
+}
+
+/// Value of a registry property feature descriptor
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RegistryValue<'a> {
+    /// `REG_SZ`
+    String(&'a str),
+
+    /// `REG_EXPAND_SZ`
+    ExpandString(&'a str),
+
+    /// `REG_MULTI_SZ`
+    MultiString(&'a [&'a str]),
+
+    /// `REG_DWORD_LITTLE_ENDIAN`
+    Dword(u32),
+
+    /// `REG_BINARY`
+    Binary(&'a [u8]),
+}
+
+impl RegistryValue<'_> {
+    fn data_type(&self) -> u16 {
+        match self {
+            RegistryValue::String(_) => REG_SZ,
+            RegistryValue::ExpandString(_) => REG_EXPAND_SZ,
+            RegistryValue::MultiString(_) => REG_MULTI_SZ,
+            RegistryValue::Dword(_) => REG_DWORD_LITTLE_ENDIAN,
+            RegistryValue::Binary(_) => REG_BINARY,
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        match self {
+            RegistryValue::String(s) | RegistryValue::ExpandString(s) => utf16_len(s),
+            RegistryValue::MultiString(strs) => {
+                strs.iter().map(|s| utf16_len(s)).sum::<usize>() + 2
+            }
+            RegistryValue::Dword(_) => 4,
+            RegistryValue::Binary(data) => data.len(),
+        }
+    }
+
+    fn write(&self, w: &mut Writer) -> Result<()> {
+        match self {
+            RegistryValue::String(s) | RegistryValue::ExpandString(s) => w.utf16(s),
+            RegistryValue::MultiString(strs) => {
+                for s in strs.iter() {
+                    w.utf16(s)?;
+                }
+
+                w.u16(0)
+            }
+            RegistryValue::Dword(v) => w.bytes(&v.to_le_bytes()),
+            RegistryValue::Binary(data) => w.bytes(data),
+        }
+    }
+}
+
+/// Registry property feature descriptor
+///
+/// Windows stores these properties under the `Device Parameters` key of the function, where drivers
+/// and applications can look them up. WinUSB devices will usually want to set
+/// `DeviceInterfaceGUIDs` so that applications can find the device.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RegistryProperty<'a> {
+    name: &'a str,
+    value: RegistryValue<'a>,
+}
+
+impl<'a> RegistryProperty<'a> {
+    /// Creates a registry property with the given name and value.
+    pub const fn new(name: &'a str, value: RegistryValue<'a>) -> Self {
+        RegistryProperty { name, value }
+    }
+
+    /// Creates a `DeviceInterfaceGUIDs` property for binding with WinUSB. The GUID should be given
+    /// in the usual braced form, e.g. `"{8FE6D4D7-49DD-41E7-9486-49AFC6BFE475}"`.
+    pub const fn device_interface_guids(guids: &'a [&'a str]) -> Self {
+        Self::new("DeviceInterfaceGUIDs", RegistryValue::MultiString(guids))
+    }
+
+    /// Gets the property name.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Gets the property value.
+    pub fn value(&self) -> RegistryValue<'a> {
+        self.value
+    }
+
+    fn len(&self) -> usize {
+        // wLength, wDescriptorType, wPropertyDataType, wPropertyNameLength, wPropertyDataLength
+        10 + utf16_len(self.name) + self.value.data_len()
+    }
+
+    fn write(&self, w: &mut Writer) -> Result<()> {
+        w.u16(self.len() as u16)?;
+        w.u16(MS_OS_20_FEATURE_REG_PROPERTY)?;
+        w.u16(self.value.data_type())?;
+        w.u16(utf16_len(self.name) as u16)?;
+        w.utf16(self.name)?;
+        w.u16(self.value.data_len() as u16)?;
+        self.value.write(w)
+    }
+}
+
+/// Microsoft OS 2.0 descriptors
+///
+/// When set on a [`CdcAcmClass`](crate::CdcAcmClass) or [`SerialPort`](crate::SerialPort), the
+/// class adds an MS OS 2.0 platform capability to the BOS descriptor and answers the vendor request
+/// Windows sends to fetch the descriptor set. The descriptor set contains a single function subset
+/// scoped to the CDC-ACM interfaces, which holds the compatible ID and registry properties.
+///
+/// Windows only asks for BOS descriptors if the device reports USB 2.1 or later, which is the
+/// default `usb_rev` of `UsbDeviceBuilder`. Function subsets are only valid for
+/// composite devices, so the device should also use the IAD device class triple (`0xef`, `0x02`,
+/// `0x01`) instead of [`USB_CLASS_CDC`](crate::USB_CLASS_CDC). Windows only reads one MS OS 2.0
+/// descriptor set per device, so only one class in a composite device should provide one.
+///
+/// Without a compatible ID, Windows 10 and later bind CDC-ACM functions to `usbser.sys` based on
+/// the class codes, and the registry properties are still applied.
+///
+/// The descriptor set is sent in a single control transfer, so it must fit into the control buffer
+/// of `usb-device`, which is 128 bytes by default. A WinUSB descriptor set with a
+/// `DeviceInterfaceGUIDs` property is 178 bytes, so it requires the `control-buffer-256` feature
+/// of this crate, which enables the feature of the same name in `usb-device`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MsOsDescriptors<'a> {
+    vendor_code: u8,
+    compatible_id: Option<CompatibleId>,
+    properties: &'a [RegistryProperty<'a>],
+}
+
+impl<'a> MsOsDescriptors<'a> {
+    /// Creates a new MS OS 2.0 descriptor set. `vendor_code` is the `bRequest` value Windows will
+    /// use to request the descriptor set, and must not clash with other vendor requests of the
+    /// device.
+    pub const fn new(
+        vendor_code: u8,
+        compatible_id: Option<CompatibleId>,
+        properties: &'a [RegistryProperty<'a>],
+    ) -> Self {
+        MsOsDescriptors {
+            vendor_code,
+            compatible_id,
+            properties,
+        }
+    }
+
+    /// Creates a descriptor set that binds the function to WinUSB with the given registry
+    /// properties, which should usually include
+    /// [`device_interface_guids`](RegistryProperty::device_interface_guids).
+    pub const fn winusb(vendor_code: u8, properties: &'a [RegistryProperty<'a>]) -> Self {
+        Self::new(vendor_code, Some(CompatibleId::WINUSB), properties)
+    }
+
+    /// Gets the vendor request code used to retrieve the descriptor set.
+    pub fn vendor_code(&self) -> u8 {
+        self.vendor_code
+    }
+
+    /// Gets the compatible ID, if any.
+    pub fn compatible_id(&self) -> Option<CompatibleId> {
+        self.compatible_id
+    }
+
+    /// Gets the registry properties.
+    pub fn properties(&self) -> &'a [RegistryProperty<'a>] {
+        self.properties
+    }
+
+    fn function_subset_len(&self) -> usize {
+        FUNCTION_SUBSET_HEADER_LEN
+            + self.compatible_id.map_or(0, |_| COMPATIBLE_ID_LEN)
+            + self.properties.iter().map(|p| p.len()).sum::<usize>()
+    }
+
+    /// Gets the total length of the descriptor set in bytes.
+    pub fn total_len(&self) -> usize {
+        SET_HEADER_LEN + CONFIGURATION_SUBSET_HEADER_LEN + self.function_subset_len()
+    }
+
+    /// Gets the data of the MS OS 2.0 platform capability descriptor, excluding the bLength,
+    /// bDescriptorType and bDevCapabilityType fields written by `BosWriter`.
+    pub(crate) fn platform_capability(&self) -> [u8; 25] {
+        let mut data = [0u8; 25];
+
+        // data[0] is bReserved
+        data[1..17].copy_from_slice(&MS_OS_20_PLATFORM_UUID);
+        data[17..21].copy_from_slice(&WINDOWS_VERSION_8_1.to_le_bytes());
+        data[21..23].copy_from_slice(&(self.total_len() as u16).to_le_bytes());
+        data[23] = self.vendor_code;
+        // data[24] is bAltEnumCode, alternate enumeration is not supported
+
+        data
+    }
+
+    /// Writes the descriptor set for a function starting at interface `first_interface` into
+    /// `buf`, and returns the number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - The descriptor set doesn't fit
+    ///   into `buf`.
+    pub fn write_descriptor_set(&self, first_interface: u8, buf: &mut [u8]) -> Result<usize> {
+        let mut w = Writer { buf, pos: 0 };
+
+        w.u16(SET_HEADER_LEN as u16)?;
+        w.u16(MS_OS_20_SET_HEADER_DESCRIPTOR)?;
+        w.bytes(&WINDOWS_VERSION_8_1.to_le_bytes())?;
+        w.u16(self.total_len() as u16)?;
+
+        w.u16(CONFIGURATION_SUBSET_HEADER_LEN as u16)?;
+        w.u16(MS_OS_20_SUBSET_HEADER_CONFIGURATION)?;
+        // bConfigurationValue is actually the configuration index, bReserved
+        w.bytes(&[0x00, 0x00])?;
+        w.u16((CONFIGURATION_SUBSET_HEADER_LEN + self.function_subset_len()) as u16)?;
+
+        w.u16(FUNCTION_SUBSET_HEADER_LEN as u16)?;
+        w.u16(MS_OS_20_SUBSET_HEADER_FUNCTION)?;
+        w.bytes(&[first_interface, 0x00])?;
+        w.u16(self.function_subset_len() as u16)?;
+
+        if let Some(id) = self.compatible_id {
+            w.u16(COMPATIBLE_ID_LEN as u16)?;
+            w.u16(MS_OS_20_FEATURE_COMPATIBLE_ID)?;
+            w.bytes(&id.compatible_id)?;
+            w.bytes(&id.sub_compatible_id)?;
+        }
+
+        for property in self.properties.iter() {
+            property.write(&mut w)?;
+        }
+
+        Ok(w.pos)
+    }
+}
+
+/// Length of a string in bytes when encoded as null-terminated UTF-16LE.
+fn utf16_len(s: &str) -> usize {
+    (s.encode_utf16().count() + 1) * 2
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, data: &[u8]) -> Result<()> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn u16(&mut self, value: u16) -> Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn utf16(&mut self, s: &str) -> Result<()> {
+        for c in s.encode_utf16() {
+            self.u16(c)?;
+        }
+
+        self.u16(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_capability() {
+        let d = MsOsDescriptors::new(0x42, None, &[]);
+
+        assert_eq!(
+            d.platform_capability(),
+            [
+                0x00, // bReserved
+                0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64,
+                0x8a, 0x9f, // PlatformCapabilityUUID
+                0x00, 0x00, 0x03, 0x06, // dwWindowsVersion
+                0x1a, 0x00, // wMSOSDescriptorSetTotalLength
+                0x42, // bMS_VendorCode
+                0x00, // bAltEnumCode
+            ]
+        );
+    }
+
+    #[test]
+    fn winusb_descriptor_set() {
+        const PROPERTIES: &[RegistryProperty] = &[RegistryProperty::new(
+            "A",
+            RegistryValue::MultiString(&["B"]),
+        )];
+        let d = MsOsDescriptors::winusb(0x01, PROPERTIES);
+        let mut buf = [0u8; 128];
+
+        let len = d.write_descriptor_set(2, &mut buf).unwrap();
+
+        assert_eq!(len, d.total_len());
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, 0x42, 0x00, // set header
+                0x08, 0x00, 0x01, 0x00, 0x00, 0x00, 0x38, 0x00, // configuration subset
+                0x08, 0x00, 0x02, 0x00, 0x02, 0x00, 0x30, 0x00, // function subset
+                0x14, 0x00, 0x03, 0x00, b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // compatible ID
+                0x14, 0x00, 0x04, 0x00, 0x07, 0x00, 0x04, 0x00, b'A', 0x00, 0x00, 0x00, 0x06, 0x00,
+                b'B', 0x00, 0x00, 0x00, 0x00, 0x00, // registry property
+            ][..]
+        );
+    }
+
+    #[test]
+    fn device_interface_guids() {
+        const PROPERTIES: &[RegistryProperty] = &[RegistryProperty::device_interface_guids(&[
+            "{8FE6D4D7-49DD-41E7-9486-49AFC6BFE475}",
+        ])];
+        let d = MsOsDescriptors::winusb(0x01, PROPERTIES);
+        let mut buf = [0u8; 256];
+
+        assert_eq!(d.total_len(), 178);
+        assert_eq!(d.write_descriptor_set(0, &mut buf), Ok(178));
+        assert_eq!(&buf[46..50], &[0x84, 0x00, 0x04, 0x00]);
+        assert_eq!(&buf[176..178], &[0x00, 0x00]);
+    }
+
+    #[test]
+    fn buffer_overflow() {
+        let d = MsOsDescriptors::winusb(0x01, &[]);
+        let mut buf = [0u8; 16];
+
+        assert_eq!(
+            d.write_descriptor_set(0, &mut buf),
+            Err(UsbError::BufferOverflow)
+        );
+    }
+}
@@ -1,10 +1,15 @@
 use crate::buffer::{Buffer, DefaultBufferStore};
 use crate::cdc_acm::*;
+use crate::ms_os::MsOsDescriptors;
+use crate::stats::{self, Counters};
 use core::borrow::BorrowMut;
+use core::convert::Infallible;
 use core::slice;
+use core::task::Waker;
 use usb_device::Result;
 use usb_device::class_prelude::*;
 use usb_device::descriptor::lang_id::LangID;
+use usb_device::device::UsbDeviceState;
 
 /// USB (CDC-ACM) serial port with built-in buffering to implement stream-like behavior.
 ///
@@ -20,6 +25,70 @@ where
     pub(crate) read_buf: Buffer<RS>,
     pub(crate) write_buf: Buffer<WS>,
     write_state: WriteState,
+    in_flight: usize,
+    write_policy: WritePolicy,
+    data_lost: bool,
+    counters: Counters,
+    read_refused: bool,
+    device_state: UsbDeviceState,
+    configured: bool,
+    suspend_policy: SuspendWritePolicy,
+    remote_wakeup_enabled: bool,
+    wakeup_interval_ms: Option<u32>,
+    wakeup_pending: bool,
+    last_wakeup_ms: Option<u32>,
+    eager_read: bool,
+    read_notify: Option<fn()>,
+    read_waker: Option<Waker>,
+    dtr_required: bool,
+    pub(crate) connection_reset: bool,
+    keep_write_on_reset: bool,
+    reset_report: Option<ResetReport>,
+    discarding_line: bool,
+}
+
+/// What [`SerialPort::write`] does when the write buffer doesn't have room for new data
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum WritePolicy {
+    /// Write as much as fits and return `WouldBlock` if nothing fits. This is the default.
+    #[default]
+    Block,
+
+    /// Discard the oldest unsent data to make room for new data. If the data is larger than the
+    /// whole buffer, only the end of it is kept.
+    OverwriteOldest,
+
+    /// Like `OverwriteOldest`, but only discard whole records terminated by the given delimiter
+    /// byte, such as `b'\n'` for line based logs. If the buffer contains no complete record, all of
+    /// it is discarded.
+    OverwriteOldestRecords(u8),
+}
+
+/// What [`SerialPort::write`] does while the bus is suspended
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum SuspendWritePolicy {
+    /// Write to the buffer as usual, so the data is sent after the bus is resumed. This is the
+    /// default.
+    #[default]
+    Buffer,
+
+    /// Discard the data and report it as written. The loss is reported by
+    /// [`SerialPort::take_data_lost`].
+    Drop,
+
+    /// Return `InvalidState` without writing anything.
+    Error,
+}
+
+/// Data discarded by bus resets, returned by [`SerialPort::take_reset`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ResetReport {
+    /// Number of received bytes that hadn't been read yet
+    pub read_discarded: usize,
+
+    /// Number of bytes that hadn't been sent yet, including the packet that was being sent. A zero
+    /// length packet counts as one byte, like in [`SerialPort::pending_tx`].
+    pub write_discarded: usize,
 }
 
 /// If this many full size packets have been sent in a row, a short packet will be sent so that the
@@ -54,8 +123,8 @@ where
     /// Same as SerialPort::new, but allows specifying the names of the interfaces
     pub fn new_with_interface_names<'alloc: 'a>(
         alloc: &'alloc UsbBusAllocator<B>,
-        comm_if_name: Option<&'static str>,
-        data_if_name: Option<&'static str>,
+        comm_if_name: Option<&'a str>,
+        data_if_name: Option<&'a str>,
     ) -> SerialPort<'a, B, DefaultBufferStore, DefaultBufferStore> {
         SerialPort::new_with_store_and_interface_names(
             alloc,
@@ -65,6 +134,20 @@ where
             data_if_name,
         )
     }
+
+    /// Same as SerialPort::new, but allows specifying the function and interface names in one or
+    /// more languages. See [`CdcAcmClass::new_with_strings`].
+    pub fn new_with_strings<'alloc: 'a>(
+        alloc: &'alloc UsbBusAllocator<B>,
+        strings: &'a [PortStrings<'a>],
+    ) -> SerialPort<'a, B, DefaultBufferStore, DefaultBufferStore> {
+        SerialPort::new_with_store_and_strings(
+            alloc,
+            DefaultBufferStore::default(),
+            DefaultBufferStore::default(),
+            strings,
+        )
+    }
 }
 
 impl<'a, B, RS, WS> SerialPort<'a, B, RS, WS>
@@ -87,14 +170,62 @@ where
         alloc: &'alloc UsbBusAllocator<B>,
         read_store: RS,
         write_store: WS,
-        comm_if_name: Option<&'static str>,
-        data_if_name: Option<&'static str>,
+        comm_if_name: Option<&'a str>,
+        data_if_name: Option<&'a str>,
+    ) -> SerialPort<'a, B, RS, WS> {
+        Self::new_with_class(
+            CdcAcmClass::new_with_interface_names(alloc, 64, comm_if_name, data_if_name),
+            read_store,
+            write_store,
+        )
+    }
+
+    /// Creates a new USB serial port with the provided UsbBus and buffer backing stores, and
+    /// function and interface names in one or more languages. See
+    /// [`CdcAcmClass::new_with_strings`].
+    pub fn new_with_store_and_strings<'alloc: 'a>(
+        alloc: &'alloc UsbBusAllocator<B>,
+        read_store: RS,
+        write_store: WS,
+        strings: &'a [PortStrings<'a>],
+    ) -> SerialPort<'a, B, RS, WS> {
+        Self::new_with_class(
+            CdcAcmClass::new_with_strings(alloc, 64, strings),
+            read_store,
+            write_store,
+        )
+    }
+
+    fn new_with_class(
+        inner: CdcAcmClass<'a, B>,
+        read_store: RS,
+        write_store: WS,
     ) -> SerialPort<'a, B, RS, WS> {
         SerialPort {
-            inner: CdcAcmClass::new_with_interface_names(alloc, 64, comm_if_name, data_if_name),
+            inner,
             read_buf: Buffer::new(read_store),
             write_buf: Buffer::new(write_store),
             write_state: WriteState::Idle,
+            in_flight: 0,
+            write_policy: WritePolicy::Block,
+            data_lost: false,
+            counters: Counters::default(),
+            read_refused: false,
+            device_state: UsbDeviceState::Default,
+            configured: false,
+            suspend_policy: SuspendWritePolicy::Buffer,
+            remote_wakeup_enabled: false,
+            wakeup_interval_ms: None,
+            wakeup_pending: false,
+            last_wakeup_ms: None,
+            eager_read: false,
+            read_notify: None,
+            read_waker: None,
+            dtr_required: false,
+            connection_reset: false,
+            keep_write_on_reset: false,
+            reset_report: None,
+            discarding_line: false,
         }
     }
 
@@ -103,6 +234,23 @@ where
         self.inner.line_coding()
     }
 
+    /// Gets the line coding that is used until the host sets one, and after a bus reset.
+    pub fn default_line_coding(&self) -> &LineCoding {
+        self.inner.default_line_coding()
+    }
+
+    /// Sets the line coding that is used until the host sets one, and after a bus reset. See
+    /// [`CdcAcmClass::set_default_line_coding`].
+    pub fn set_default_line_coding(&mut self, line_coding: LineCoding) {
+        self.inner.set_default_line_coding(line_coding);
+    }
+
+    /// Sets a policy that decides how line codings requested by the host are handled. See
+    /// [`CdcAcmClass::set_line_coding_policy`].
+    pub fn set_line_coding_policy(&mut self, policy: Option<LineCodingPolicy>) {
+        self.inner.set_line_coding_policy(policy);
+    }
+
     /// Gets the DTR (data terminal ready) state
     pub fn dtr(&self) -> bool {
         self.inner.dtr()
@@ -113,16 +261,286 @@ where
         self.inner.rts()
     }
 
+    /// Returns `true` if the `embedded-io` implementations and the blocking methods report
+    /// `NotConnected` while DTR is not set.
+    pub fn dtr_required(&self) -> bool {
+        self.dtr_required
+    }
+
+    /// Sets whether the `embedded-io` implementations and the blocking methods report
+    /// [`io::Error::NotConnected`](crate::io::Error::NotConnected) while DTR is not set, instead of
+    /// waiting for the host. Disabled by default, because some host applications never set DTR.
+    pub fn set_dtr_required(&mut self, required: bool) {
+        self.dtr_required = required;
+    }
+
+    /// Gets the Microsoft OS 2.0 descriptors provided by this port, if any.
+    pub fn ms_os_descriptors(&self) -> Option<&MsOsDescriptors<'a>> {
+        self.inner.ms_os_descriptors()
+    }
+
+    /// Sets the Microsoft OS 2.0 descriptors provided by this port. See
+    /// [`CdcAcmClass::set_ms_os_descriptors`].
+    pub fn set_ms_os_descriptors(
+        &mut self,
+        descriptors: Option<MsOsDescriptors<'a>>,
+    ) -> Result<()> {
+        self.inner.set_ms_os_descriptors(descriptors)
+    }
+
+    /// Sends a SERIAL_STATE notification to the host. See [`CdcAcmClass::send_serial_state`].
+    pub fn send_serial_state(&mut self, state: SerialState) -> Result<()> {
+        self.inner.send_serial_state(state)?;
+
+        if self.device_state == UsbDeviceState::Suspend {
+            self.wakeup_pending = true;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the device state tracked by the port. Classes are not notified of suspend and
+    /// resume, so this should be called with `UsbDevice::state()` and
+    /// `UsbDevice::remote_wakeup_enabled()` after each call to `UsbDevice::poll`.
+    pub fn set_device_state(&mut self, state: UsbDeviceState, remote_wakeup_enabled: bool) {
+        self.device_state = state;
+        self.remote_wakeup_enabled = remote_wakeup_enabled;
+
+        // The configuration is kept while suspended.
+        if state != UsbDeviceState::Suspend {
+            self.configured = state == UsbDeviceState::Configured;
+            self.wakeup_pending = false;
+        }
+    }
+
+    /// Gets the device state last set with [`set_device_state`](SerialPort::set_device_state), or
+    /// `Default` after a bus reset.
+    pub fn device_state(&self) -> UsbDeviceState {
+        self.device_state
+    }
+
+    /// Returns `true` if the bus is suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.device_state == UsbDeviceState::Suspend
+    }
+
+    /// Returns `true` if the device has been configured by the host, even if the bus is currently
+    /// suspended.
+    pub fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    /// Returns `true` if the device is configured and the bus is not suspended, so data can
+    /// actually be transferred.
+    pub fn is_usable(&self) -> bool {
+        self.device_state == UsbDeviceState::Configured
+    }
+
+    /// Gets the policy for writes while the bus is suspended.
+    pub fn suspend_write_policy(&self) -> SuspendWritePolicy {
+        self.suspend_policy
+    }
+
+    /// Sets the policy for writes while the bus is suspended. See [`SuspendWritePolicy`]. The
+    /// device state must be kept up to date with
+    /// [`set_device_state`](SerialPort::set_device_state).
+    pub fn set_suspend_write_policy(&mut self, policy: SuspendWritePolicy) {
+        self.suspend_policy = policy;
+    }
+
+    /// Gets the minimum interval between remote wakeup requests in milliseconds, or `None` if
+    /// remote wakeup is disabled.
+    pub fn remote_wakeup_interval_ms(&self) -> Option<u32> {
+        self.wakeup_interval_ms
+    }
+
+    /// Enables or disables requesting remote wakeup when data or a SERIAL_STATE notification is
+    /// queued while the bus is suspended. If the host doesn't resume the bus, the request is
+    /// repeated at most once per `interval_ms`. The default is disabled.
+    ///
+    /// The device must be built with `supports_remote_wakeup(true)`, and the host must have enabled
+    /// remote wakeup. See [`take_wakeup_request`](SerialPort::take_wakeup_request).
+    pub fn set_remote_wakeup_interval_ms(&mut self, interval_ms: Option<u32>) {
+        self.wakeup_interval_ms = interval_ms;
+    }
+
+    /// Returns `true` if remote wakeup should be signaled now, and starts a new rate limiting
+    /// interval. `usb-device` has no API for signaling remote wakeup, so the application should do
+    /// it with its USB peripheral driver. `now_ms` is a millisecond timestamp, which may wrap
+    /// around.
+    ///
+    /// The device state must be kept up to date with
+    /// [`set_device_state`](SerialPort::set_device_state).
+    pub fn take_wakeup_request(&mut self, now_ms: u32) -> bool {
+        let Some(interval_ms) = self.wakeup_interval_ms else {
+            return false;
+        };
+
+        if !self.wakeup_pending
+            || !self.remote_wakeup_enabled
+            || self.device_state != UsbDeviceState::Suspend
+        {
+            return false;
+        }
+
+        if let Some(last) = self.last_wakeup_ms
+            && now_ms.wrapping_sub(last) < interval_ms
+        {
+            return false;
+        }
+
+        self.last_wakeup_ms = Some(now_ms);
+        true
+    }
+
+    /// Returns `true` if unsent data is kept across bus resets.
+    pub fn keep_write_on_reset(&self) -> bool {
+        self.keep_write_on_reset
+    }
+
+    /// Sets whether unsent data in the write buffer is kept across bus resets instead of being
+    /// discarded. This is useful for hosts that reset the bus during enumeration, which would
+    /// otherwise lose output written early, such as a boot banner. The packet that is being sent
+    /// when the bus is reset is still lost.
+    ///
+    /// While this is enabled, nothing is sent until the host has configured the device, and the
+    /// kept data is sent by the next write or flush after that. The device state must be kept up
+    /// to date with [`set_device_state`](SerialPort::set_device_state). The default is disabled.
+    pub fn set_keep_write_on_reset(&mut self, keep: bool) {
+        self.keep_write_on_reset = keep;
+    }
+
+    /// Returns how much buffered data was discarded if the bus has been reset since the last call,
+    /// and clears the report. Counts from multiple resets are added up.
+    pub fn take_reset(&mut self) -> Option<ResetReport> {
+        self.reset_report.take()
+    }
+
+    /// Returns `true` if received packets are moved into the read buffer as soon as they arrive.
+    pub fn eager_read(&self) -> bool {
+        self.eager_read
+    }
+
+    /// Sets whether received packets are moved into the read buffer from
+    /// [`UsbClass::endpoint_out`] as soon as they arrive, instead of only when the application
+    /// reads. This frees the endpoint sooner, which improves throughput if `UsbDevice::poll` is
+    /// called from the USB interrupt. The default is disabled.
+    pub fn set_eager_read(&mut self, eager: bool) {
+        self.eager_read = eager;
+    }
+
+    /// Sets a function that is called from [`UsbClass::endpoint_out`] when data has arrived. It
+    /// runs in the context that polls the device, such as the USB interrupt, and should only signal
+    /// the application, for example by setting a flag or pending a task.
+    pub fn set_read_notify(&mut self, notify: Option<fn()>) {
+        self.read_notify = notify;
+    }
+
+    /// Registers a waker that is woken once when data has arrived, for use by async readers. A
+    /// waker must be registered again after each wakeup.
+    pub fn register_read_waker(&mut self, waker: &Waker) {
+        match &self.read_waker {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => self.read_waker = Some(waker.clone()),
+        }
+    }
+
+    /// Gets the subclass code reported for the communication interface.
+    pub fn comm_subclass(&self) -> u8 {
+        self.inner.comm_subclass()
+    }
+
+    /// Sets the subclass code reported for the communication interface. See
+    /// [`CdcAcmClass::set_comm_subclass`].
+    pub fn set_comm_subclass(&mut self, subclass: u8) {
+        self.inner.set_comm_subclass(subclass);
+    }
+
+    /// Gets the protocol reported for the communication interface.
+    pub fn comm_protocol(&self) -> CommProtocol {
+        self.inner.comm_protocol()
+    }
+
+    /// Sets the protocol reported for the communication interface. See
+    /// [`CdcAcmClass::set_comm_protocol`].
+    pub fn set_comm_protocol(&mut self, protocol: CommProtocol) {
+        self.inner.set_comm_protocol(protocol);
+    }
+
+    /// Returns `true` if the data interface reports the vendor-specific class.
+    pub fn vendor_data_interface(&self) -> bool {
+        self.inner.vendor_data_interface()
+    }
+
+    /// Sets whether the data interface reports the vendor-specific class. See
+    /// [`CdcAcmClass::set_vendor_data_interface`].
+    pub fn set_vendor_data_interface(&mut self, vendor: bool) {
+        self.inner.set_vendor_data_interface(vendor);
+    }
+
+    /// Gets a snapshot of the statistics counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::SerialStats {
+        self.counters.get()
+    }
+
+    /// Resets all statistics counters to zero.
+    #[cfg(feature = "stats")]
+    pub fn clear_stats(&mut self) {
+        self.counters.clear();
+    }
+
+    /// Gets the policy for writes that don't fit into the write buffer.
+    pub fn write_policy(&self) -> WritePolicy {
+        self.write_policy
+    }
+
+    /// Sets the policy for writes that don't fit into the write buffer. See [`WritePolicy`].
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    /// Returns `true` if unsent data has been discarded by an overwriting [`WritePolicy`] or by
+    /// [`SuspendWritePolicy::Drop`] since the last call, and clears the flag. This can be used to
+    /// send a marker that lets the host resync.
+    pub fn take_data_lost(&mut self) -> bool {
+        core::mem::take(&mut self.data_lost)
+    }
+
     /// Writes bytes from `data` into the port and returns the number of bytes written.
     ///
+    /// With an overwriting [`WritePolicy`], all of `data` is always accepted and older unsent data
+    /// is discarded if necessary.
+    ///
     /// # Errors
     ///
     /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes could be written because the
     ///   buffers are full.
     ///
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The bus is suspended and the
+    ///   suspend write policy is [`SuspendWritePolicy::Error`].
+    ///
     /// Other errors from `usb-device` may also be propagated.
     pub fn write(&mut self, data: &[u8]) -> Result<usize> {
-        let count = self.write_buf.write(data);
+        if self.device_state == UsbDeviceState::Suspend {
+            match self.suspend_policy {
+                SuspendWritePolicy::Buffer => {}
+                SuspendWritePolicy::Drop => {
+                    self.data_lost |= !data.is_empty();
+                    return Ok(data.len());
+                }
+                SuspendWritePolicy::Error => return Err(UsbError::InvalidState),
+            }
+        }
+
+        let count = match self.write_policy {
+            WritePolicy::Block => self.write_buf.write(data),
+            _ => self.write_overwriting(data),
+        };
+
+        if count > 0 && self.device_state == UsbDeviceState::Suspend {
+            self.wakeup_pending = true;
+        }
 
         match self.flush() {
             Ok(_) | Err(UsbError::WouldBlock) => {}
@@ -132,21 +550,226 @@ where
         };
 
         if count == 0 {
+            if !data.is_empty() {
+                self.counters
+                    .update(|s| stats::add(&mut s.write_would_block, 1));
+            }
+
             Err(UsbError::WouldBlock)
         } else {
             Ok(count)
         }
     }
 
+    /// Reserves `max_count` bytes in the write buffer and passes them to `f`, which writes data
+    /// into them in place and returns the number of bytes to commit. The committed data is then
+    /// flushed. Returns the number of bytes committed.
+    ///
+    /// With an overwriting [`WritePolicy`], older unsent data is discarded to make room if
+    /// necessary. With [`SuspendWritePolicy::Drop`] the data is discarded after `f` returns.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There are less than `max_count` bytes
+    ///   of room in the write buffer. `f` is not called.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - `max_count` is larger than the
+    ///   whole write buffer.
+    /// * [`InvalidState`](usb_device::UsbError::InvalidState) - The bus is suspended and the
+    ///   suspend write policy is [`SuspendWritePolicy::Error`].
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn write_with(
+        &mut self,
+        max_count: usize,
+        f: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<usize> {
+        let count = self.write_reserved(max_count, self.write_policy != WritePolicy::Block, f)?;
+
+        match self.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => Ok(count),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Writes `len` bytes produced by `f` if they fit into the write buffer, or nothing at all,
+    /// without flushing. Older unsent data is never discarded to make room, so this is used by the
+    /// wrappers for frames, echoes and responses that must not be split or corrupt other output.
+    /// The suspend write policy applies as for other writes.
+    pub(crate) fn write_all_with(&mut self, len: usize, f: impl FnOnce(&mut [u8])) -> Result<()> {
+        self.write_reserved(len, false, |buf| {
+            f(buf);
+            len
+        })
+        .map(|_| ())
+    }
+
+    /// Writes all parts as one unit with [`write_all_with`](SerialPort::write_all_with).
+    pub(crate) fn write_parts(&mut self, parts: &[&[u8]]) -> Result<()> {
+        let len = parts.iter().map(|part| part.len()).sum();
+
+        self.write_all_with(len, |out| {
+            let mut pos = 0;
+
+            for part in parts {
+                out[pos..pos + part.len()].copy_from_slice(part);
+                pos += part.len();
+            }
+        })
+    }
+
+    /// Reserves `max_count` bytes in the write buffer and commits the bytes written by `f`
+    /// according to the suspend write policy, discarding older data to make room first if
+    /// `overwrite` is set.
+    fn write_reserved(
+        &mut self,
+        max_count: usize,
+        overwrite: bool,
+        f: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<usize> {
+        if max_count > self.write_buf.capacity() {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let suspended = self.device_state == UsbDeviceState::Suspend;
+        let discard = match self.suspend_policy {
+            _ if !suspended => false,
+            SuspendWritePolicy::Buffer => false,
+            SuspendWritePolicy::Drop => true,
+            SuspendWritePolicy::Error => return Err(UsbError::InvalidState),
+        };
+
+        if overwrite && !discard {
+            self.make_room(max_count, 0);
+        }
+
+        let mut reserved = max_count == 0;
+        let mut count = 0;
+
+        self.write_buf
+            .write_all(max_count, |data| {
+                reserved = true;
+                count = f(data).min(max_count);
+
+                Ok::<usize, Infallible>(if discard { 0 } else { count })
+            })
+            .ok();
+
+        if !reserved {
+            self.counters
+                .update(|s| stats::add(&mut s.write_would_block, 1));
+
+            return Err(UsbError::WouldBlock);
+        }
+
+        if discard {
+            self.data_lost |= count > 0;
+        } else if count > 0 && suspended {
+            self.wakeup_pending = true;
+        }
+
+        Ok(count)
+    }
+
+    /// Writes all of `data` if it fits into the write buffer, or nothing at all. Messages written
+    /// this way are never split or interleaved with other writes. Older unsent data is never
+    /// discarded to make room, whatever the [`WritePolicy`].
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There are less than `data.len()` bytes
+    ///   of room in the write buffer. Nothing was written.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - `data` is larger than the whole
+    ///   write buffer.
+    ///
+    /// Other errors are the same as for [`write_with`](SerialPort::write_with).
+    pub fn write_all_or_nothing(&mut self, data: &[u8]) -> Result<()> {
+        self.write_parts(&[data])?;
+
+        match self.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Gets the number of bytes that can currently be written to the write buffer without
+    /// discarding anything.
+    pub fn writable_space(&self) -> usize {
+        self.write_buf.available_write()
+    }
+
+    /// Gets the number of bytes that can currently be written to the write buffer without
+    /// discarding anything. This is the same as [`writable_space`](SerialPort::writable_space).
+    pub fn write_capacity(&self) -> usize {
+        self.writable_space()
+    }
+
+    /// Gets the number of written bytes that the host hasn't received yet, including the packet
+    /// that is currently being sent. A zero length packet that ends the transfer counts as one
+    /// byte, both while it still has to be sent and while it's being sent. When this is zero, the
+    /// port has nothing left to send.
+    pub fn pending_tx(&self) -> usize {
+        let zlp =
+            matches!(self.write_state, WriteState::Full(_)) && self.write_buf.available_read() == 0;
+
+        self.write_buf.available_read() + self.in_flight + zlp as usize
+    }
+
+    /// Writes all of `data` into the write buffer, discarding old data according to the write
+    /// policy if it doesn't fit.
+    fn write_overwriting(&mut self, data: &[u8]) -> usize {
+        let kept = &data[data.len().saturating_sub(self.write_buf.capacity())..];
+        self.make_room(kept.len(), data.len() - kept.len());
+
+        self.write_buf.write(kept);
+        data.len()
+    }
+
+    /// Discards old data according to the write policy so that `len` bytes fit into the write
+    /// buffer, and records the loss together with `lost` bytes of new data that were discarded.
+    fn make_room(&mut self, len: usize, mut lost: usize) {
+        let buf = &mut self.write_buf;
+        let shortfall = len.saturating_sub(buf.available_write());
+
+        if shortfall > 0 {
+            let count = match self.write_policy {
+                WritePolicy::OverwriteOldestRecords(delimiter) => buf.data()[shortfall - 1..]
+                    .iter()
+                    .position(|&b| b == delimiter)
+                    .map_or(buf.available_read(), |i| shortfall + i),
+                _ => shortfall,
+            };
+
+            lost += buf.discard(count);
+        }
+
+        if lost > 0 {
+            self.data_lost = true;
+            self.counters
+                .update(|s| stats::add(&mut s.bytes_overwritten, lost));
+        }
+    }
+
     /// Poll the endpoint and try to put them into the serial buffer.
     pub(crate) fn poll(&mut self) -> Result<()> {
         let Self {
-            inner, read_buf, ..
+            inner,
+            read_buf,
+            counters,
+            read_refused,
+            ..
         } = self;
 
         read_buf.write_all(inner.max_packet_size() as usize, |buf_data| {
             match inner.read_packet(buf_data) {
-                Ok(c) => Ok(c),
+                Ok(c) => {
+                    *read_refused = false;
+                    counters.update(|s| {
+                        stats::add(&mut s.packets_in, 1);
+                        stats::add(&mut s.bytes_in, c);
+                    });
+
+                    Ok(c)
+                }
                 Err(UsbError::WouldBlock) => Ok(0),
                 Err(err) => Err(err),
             }
@@ -179,14 +802,162 @@ where
         })
     }
 
+    /// Returns the data in the read buffer without copying it, after reading a packet from the
+    /// endpoint if there's room. Call [`consume`](SerialPort::consume) to remove data that has been
+    /// processed.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes available for reading.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.poll()?;
+
+        if self.read_buf.available_read() == 0 {
+            return Err(UsbError::WouldBlock);
+        }
+
+        Ok(self.read_buf.data())
+    }
+
+    /// Gets the number of bytes in the read buffer. This doesn't read packets from the endpoint, so
+    /// more data may be available from [`peek`](SerialPort::peek) or
+    /// [`read`](SerialPort::read).
+    pub fn bytes_available(&self) -> usize {
+        self.read_buf.available_read()
+    }
+
+    /// Copies bytes from the read buffer into `data` without removing them, after reading a packet
+    /// from the endpoint if there's room. Returns the number of bytes copied.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes available for reading.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn peek(&mut self, data: &mut [u8]) -> Result<usize> {
+        let buffered = self.fill_buf()?;
+        let count = buffered.len().min(data.len());
+
+        data[..count].copy_from_slice(&buffered[..count]);
+        Ok(count)
+    }
+
+    /// Removes `count` bytes returned by [`fill_buf`](SerialPort::fill_buf) from the read buffer.
+    pub fn consume(&mut self, count: usize) {
+        self.read_buf.discard(count);
+    }
+
+    /// Reads a line terminated by `delim` into `data` and returns its length. The delimiter is
+    /// removed from the read buffer but not copied.
+    ///
+    /// The delimiter is searched for in the read buffer in place, and packets are only read while
+    /// the read buffer has room for a whole max size packet. Lines up to the read buffer size minus
+    /// the max packet size are always received. Longer lines are only received if the delimiter
+    /// arrives in the packet that takes the buffered line past that size, which depends on how the
+    /// host splits the data into packets.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No complete line is available yet.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - The line doesn't fit into
+    ///   `data`, or the read buffer has no room for another packet and contains no delimiter. The
+    ///   line is discarded up to and including the next delimiter.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn read_until(&mut self, delim: u8, data: &mut [u8]) -> Result<usize> {
+        self.read_delimited(delim, false, data)
+    }
+
+    /// Like [`read_until`](SerialPort::read_until) with `b'\n'` as the delimiter, but also removes
+    /// a `b'\r'` before the delimiter.
+    pub fn read_line(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_delimited(b'\n', true, data)
+    }
+
+    fn read_delimited(&mut self, delim: u8, trim_cr: bool, data: &mut [u8]) -> Result<usize> {
+        self.poll()?;
+
+        if self.discarding_line {
+            match self.read_buf.data().iter().position(|&b| b == delim) {
+                Some(pos) => {
+                    self.read_buf.discard(pos + 1);
+                    self.discarding_line = false;
+                }
+                None => {
+                    self.read_buf.discard(self.read_buf.available_read());
+                    return Err(UsbError::WouldBlock);
+                }
+            }
+        }
+
+        let buffered = self.read_buf.data();
+
+        let Some(pos) = buffered.iter().position(|&b| b == delim) else {
+            if self.read_buf.available_write() < self.inner.max_packet_size() as usize {
+                // No more packets can be read, so the line can never be completed.
+                self.read_buf.discard(self.read_buf.available_read());
+                self.discarding_line = true;
+                return Err(UsbError::BufferOverflow);
+            }
+
+            return Err(UsbError::WouldBlock);
+        };
+
+        let mut line = &buffered[..pos];
+        if trim_cr && let [rest @ .., b'\r'] = line {
+            line = rest;
+        }
+
+        let result = match data.get_mut(..line.len()) {
+            Some(data) => {
+                data.copy_from_slice(line);
+                Ok(line.len())
+            }
+            None => Err(UsbError::BufferOverflow),
+        };
+
+        self.read_buf.discard(pos + 1);
+        result
+    }
+
+    /// Passes the data in the read buffer to `f` without copying it, and removes the number of
+    /// bytes returned by `f` from the buffer. Returns the number of bytes removed.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No bytes available for reading. `f`
+    ///   is not called.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn read_with(&mut self, f: impl FnOnce(&[u8]) -> usize) -> Result<usize> {
+        self.poll()?;
+
+        if self.read_buf.available_read() == 0 {
+            return Err(UsbError::WouldBlock);
+        }
+
+        self.read_buf
+            .read(usize::MAX, |data| Ok(f(data).min(data.len())))
+    }
+
     /// Sends as much as possible of the current write buffer. Returns `Ok` if all data that has
     /// been written has been completely written to hardware buffers `Err(WouldBlock)` if there is
     /// still data remaining, and other errors if there's an error sending data to the host. Note
     /// that even if this method returns `Ok`, data may still be in hardware buffers on either side.
     pub fn flush(&mut self) -> Result<()> {
+        // Packets written before the device is configured would be lost if the host resets the
+        // bus during enumeration.
+        if self.keep_write_on_reset && !self.configured && self.write_buf.available_read() > 0 {
+            return Err(UsbError::WouldBlock);
+        }
+
         let buf = &mut self.write_buf;
         let inner = &mut self.inner;
         let write_state = &mut self.write_state;
+        let in_flight = &mut self.in_flight;
+        let counters = &mut self.counters;
 
         let full_count = match *write_state {
             WriteState::Full(c) => c,
@@ -202,9 +973,21 @@ where
                 inner.max_packet_size()
             } as usize;
 
+            let forced_short = max_write_size < buf.available_read();
+
             buf.read(max_write_size, |buf_data| {
                 // This may return WouldBlock which will be propagated.
                 inner.write_packet(buf_data)?;
+                *in_flight = buf_data.len();
+
+                counters.update(|s| {
+                    stats::add(&mut s.packets_out, 1);
+                    stats::add(&mut s.bytes_out, buf_data.len());
+
+                    if forced_short && full_count >= SHORT_PACKET_INTERVAL {
+                        stats::add(&mut s.short_packets_forced, 1);
+                    }
+                });
 
                 *write_state = if buf_data.len() == inner.max_packet_size() as usize {
                     WriteState::Full(full_count + 1)
@@ -220,6 +1003,12 @@ where
             // Write a ZLP to complete the transaction if there's nothing else to write and the last
             // packet was a full one. This may return WouldBlock which will be propagated.
             inner.write_packet(&[])?;
+            *in_flight = 1;
+
+            counters.update(|s| {
+                stats::add(&mut s.packets_out, 1);
+                stats::add(&mut s.zlps_sent, 1);
+            });
 
             *write_state = WriteState::Short;
 
@@ -244,19 +1033,84 @@ where
         self.inner.get_configuration_descriptors(writer)
     }
 
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        self.inner.get_bos_descriptors(writer)
+    }
+
     fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
         self.inner.get_string(index, lang_id)
     }
 
     fn reset(&mut self) {
         self.inner.reset();
+
+        let read_discarded = self.read_buf.available_read();
+        // The packet in the endpoint buffer is lost either way.
+        let write_discarded = if self.keep_write_on_reset {
+            self.in_flight
+        } else {
+            self.in_flight + self.write_buf.available_read()
+        };
+
+        let report = self.reset_report.get_or_insert_default();
+        report.read_discarded += read_discarded;
+        report.write_discarded += write_discarded;
+        self.connection_reset |= read_discarded > 0 || write_discarded > 0;
+
         self.read_buf.clear();
-        self.write_buf.clear();
+        self.read_refused = false;
+        self.discarding_line = false;
+        if !self.keep_write_on_reset {
+            self.write_buf.clear();
+        }
         self.write_state = WriteState::Idle;
+        self.in_flight = 0;
+        self.device_state = UsbDeviceState::Default;
+        self.configured = false;
+        self.wakeup_pending = false;
+        self.counters.update(|s| stats::add(&mut s.resets, 1));
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.inner.read_ep().address() {
+            return;
+        }
+
+        let max_packet_size = self.inner.max_packet_size() as usize;
+        let mut received = false;
+
+        if self.eager_read {
+            while self.read_buf.available_write() >= max_packet_size {
+                let available = self.read_buf.available_read();
+
+                if self.poll().is_err() || self.read_buf.available_read() == available {
+                    break;
+                }
+
+                received = true;
+            }
+        }
+
+        // The packet can't be read until the application makes room. It's only counted once even
+        // if the bus keeps reporting it.
+        if !received && self.read_buf.available_write() < max_packet_size && !self.read_refused {
+            self.read_refused = true;
+            self.counters
+                .update(|s| stats::add(&mut s.read_buffer_full, 1));
+        }
+
+        if let Some(notify) = self.read_notify {
+            notify();
+        }
+
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
     }
 
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
         if addr == self.inner.write_ep().address() {
+            self.in_flight = 0;
             self.flush().ok();
         }
     }
@@ -266,7 +1120,14 @@ where
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
+        let line_state = (self.inner.dtr(), self.inner.rts());
+
         self.inner.control_out(xfer);
+
+        if (self.inner.dtr(), self.inner.rts()) != line_state {
+            self.counters
+                .update(|s| stats::add(&mut s.line_state_changes, 1));
+        }
     }
 }
 
@@ -313,3 +1174,471 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_bus::{self, TestBus, read_ep, write_ep};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    #[test]
+    fn overwrite_oldest_records() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 8], [0u8; 8]);
+        let _dev = test_bus::device(&alloc);
+
+        serial.set_write_policy(WritePolicy::OverwriteOldestRecords(b'\n'));
+
+        // The first write goes straight to the endpoint, and the rest stay in the buffer
+        assert_eq!(serial.write(b"ab\n"), Ok(3));
+        assert_eq!(serial.write(b"cd\n"), Ok(3));
+        assert_eq!(serial.write(b"ef\n"), Ok(3));
+        assert!(!serial.take_data_lost());
+
+        assert_eq!(serial.write(b"gh\n"), Ok(3));
+        assert!(serial.take_data_lost());
+        assert!(!serial.take_data_lost());
+
+        assert_eq!(host.read(write_ep()).unwrap(), b"ab\n");
+        serial.flush().ok();
+        assert_eq!(host.read(write_ep()).unwrap(), b"ef\ngh\n");
+    }
+
+    #[test]
+    fn overwrite_oldest() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 8], [0u8; 4]);
+        let _dev = test_bus::device(&alloc);
+
+        serial.set_write_policy(WritePolicy::OverwriteOldest);
+
+        assert_eq!(serial.write(b"01"), Ok(2));
+        assert_eq!(serial.write(b"234567"), Ok(6));
+        assert!(serial.take_data_lost());
+
+        assert_eq!(host.read(write_ep()).unwrap(), b"01");
+        serial.flush().ok();
+        assert_eq!(host.read(write_ep()).unwrap(), b"4567");
+    }
+
+    #[test]
+    fn remote_wakeup() {
+        let (alloc, _host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+
+        serial.set_remote_wakeup_interval_ms(Some(100));
+        serial.set_device_state(UsbDeviceState::Suspend, true);
+        assert!(!serial.take_wakeup_request(0));
+
+        serial.write(b"a").unwrap();
+        assert!(serial.take_wakeup_request(0));
+        assert!(!serial.take_wakeup_request(99));
+        assert!(serial.take_wakeup_request(100));
+
+        serial.set_device_state(UsbDeviceState::Configured, true);
+        serial.set_device_state(UsbDeviceState::Suspend, true);
+        assert!(!serial.take_wakeup_request(300));
+
+        serial.send_serial_state(SerialState::DSR).unwrap();
+        serial.set_device_state(UsbDeviceState::Suspend, false);
+        assert!(!serial.take_wakeup_request(300));
+        serial.set_device_state(UsbDeviceState::Suspend, true);
+        assert!(serial.take_wakeup_request(300));
+    }
+
+    #[test]
+    fn suspend_write_policy() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+
+        serial.set_device_state(UsbDeviceState::Configured, false);
+        assert!(serial.is_usable());
+
+        serial.set_device_state(UsbDeviceState::Suspend, false);
+        assert!(serial.is_suspended());
+        assert!(serial.is_configured());
+        assert!(!serial.is_usable());
+
+        serial.set_suspend_write_policy(SuspendWritePolicy::Error);
+        assert_eq!(serial.write(b"ab"), Err(UsbError::InvalidState));
+
+        serial.set_suspend_write_policy(SuspendWritePolicy::Drop);
+        assert_eq!(serial.write(b"cd"), Ok(2));
+        assert!(serial.take_data_lost());
+
+        serial.set_suspend_write_policy(SuspendWritePolicy::Buffer);
+        assert_eq!(serial.write(b"ef"), Ok(2));
+        assert_eq!(host.read(write_ep()).unwrap(), b"ef");
+
+        serial.reset();
+        assert_eq!(serial.device_state(), UsbDeviceState::Default);
+        assert!(!serial.is_configured());
+    }
+
+    #[test]
+    fn write_parts() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 8], [0u8; 8]);
+        let _dev = test_bus::device(&alloc);
+
+        serial.set_remote_wakeup_interval_ms(Some(100));
+        serial.set_device_state(UsbDeviceState::Suspend, true);
+
+        serial.set_suspend_write_policy(SuspendWritePolicy::Error);
+        assert_eq!(serial.write_parts(&[b"ab"]), Err(UsbError::InvalidState));
+
+        serial.set_suspend_write_policy(SuspendWritePolicy::Drop);
+        assert_eq!(serial.write_parts(&[b"ab"]), Ok(()));
+        assert!(serial.take_data_lost());
+        assert!(!serial.take_wakeup_request(0));
+
+        serial.set_suspend_write_policy(SuspendWritePolicy::Buffer);
+        assert_eq!(serial.write_parts(&[b"ab", b"cd"]), Ok(()));
+        assert!(serial.take_wakeup_request(0));
+
+        // Parts are never split, and older data is not overwritten to make room
+        serial.set_device_state(UsbDeviceState::Configured, true);
+        serial.set_write_policy(WritePolicy::OverwriteOldest);
+        assert_eq!(
+            serial.write_parts(&[b"efgh", b"i"]),
+            Err(UsbError::WouldBlock)
+        );
+        assert!(!serial.take_data_lost());
+
+        serial.flush().ok();
+        assert_eq!(host.read(write_ep()).unwrap(), b"abcd");
+    }
+
+    #[test]
+    fn zero_copy_read() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+
+        assert_eq!(serial.fill_buf(), Err(UsbError::WouldBlock));
+
+        host.write(read_ep(), b"abcd");
+        assert_eq!(serial.fill_buf().unwrap(), b"abcd");
+        serial.consume(1);
+
+        host.write(read_ep(), b"ef");
+        assert_eq!(
+            serial.read_with(|data| {
+                assert_eq!(data, b"bcdef");
+                2
+            }),
+            Ok(2)
+        );
+        assert_eq!(serial.read_with(|data| data.len() + 1), Ok(3));
+        assert_eq!(serial.read_with(|_| 0), Err(UsbError::WouldBlock));
+    }
+
+    #[test]
+    fn write_with() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 8], [0u8; 4]);
+        let _dev = test_bus::device(&alloc);
+
+        assert_eq!(serial.write_with(5, |_| 0), Err(UsbError::BufferOverflow));
+
+        let write = |data: &mut [u8]| {
+            data[..2].copy_from_slice(b"ab");
+            2
+        };
+        assert_eq!(serial.write_with(4, write), Ok(2));
+        assert_eq!(serial.write_with(3, write), Ok(2));
+        assert_eq!(serial.write_with(3, write), Err(UsbError::WouldBlock));
+
+        serial.set_write_policy(WritePolicy::OverwriteOldest);
+        assert_eq!(
+            serial.write_with(3, |data| {
+                data.copy_from_slice(b"cde");
+                3
+            }),
+            Ok(3)
+        );
+        assert!(serial.take_data_lost());
+
+        assert_eq!(host.read(write_ep()).unwrap(), b"ab");
+        serial.flush().ok();
+        assert_eq!(host.read(write_ep()).unwrap(), b"bcde");
+    }
+
+    #[test]
+    fn write_all_or_nothing() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 8], [0u8; 4]);
+        let _dev = test_bus::device(&alloc);
+
+        assert_eq!(
+            serial.write_all_or_nothing(b"01234"),
+            Err(UsbError::BufferOverflow)
+        );
+        assert_eq!(serial.write_all_or_nothing(b"012"), Ok(()));
+        assert_eq!(serial.write_all_or_nothing(b"345"), Ok(()));
+        assert_eq!(serial.writable_space(), 1);
+        assert_eq!(
+            serial.write_all_or_nothing(b"67"),
+            Err(UsbError::WouldBlock)
+        );
+        assert_eq!(serial.writable_space(), 1);
+
+        // Buffered data isn't overwritten to make room
+        serial.set_write_policy(WritePolicy::OverwriteOldest);
+        assert_eq!(
+            serial.write_all_or_nothing(b"67"),
+            Err(UsbError::WouldBlock)
+        );
+        assert!(!serial.take_data_lost());
+
+        assert_eq!(host.read(write_ep()).unwrap(), b"012");
+        serial.flush().ok();
+        assert_eq!(host.read(write_ep()).unwrap(), b"345");
+    }
+
+    #[test]
+    fn eager_read() {
+        static NOTIFIED: AtomicUsize = AtomicUsize::new(0);
+
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+
+        serial.set_read_notify(Some(|| {
+            NOTIFIED.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        host.write(read_ep(), b"ab");
+        serial.endpoint_out(read_ep());
+        assert_eq!(NOTIFIED.load(Ordering::Relaxed), 1);
+        assert_eq!(serial.read_buf.available_read(), 0);
+
+        serial.set_eager_read(true);
+        host.write(read_ep(), b"cd");
+        serial.endpoint_out(read_ep());
+        assert_eq!(NOTIFIED.load(Ordering::Relaxed), 2);
+        assert_eq!(serial.read_buf.data(), b"abcd");
+    }
+
+    #[test]
+    fn reset() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+
+        assert_eq!(serial.take_reset(), None);
+
+        // The first packet is sent, the second one is still buffered.
+        serial.write(b"ab").unwrap();
+        serial.write(b"cde").unwrap();
+        host.write(read_ep(), b"xy");
+        serial.fill_buf().unwrap();
+
+        serial.reset();
+        serial.reset();
+        assert_eq!(
+            serial.take_reset(),
+            Some(ResetReport {
+                read_discarded: 2,
+                write_discarded: 5,
+            })
+        );
+        assert_eq!(serial.take_reset(), None);
+        host.read(write_ep());
+
+        // A banner written before the host has configured the device is held back until then, so
+        // that resets during enumeration don't lose any of it.
+        let banner = b"usbd-serial test banner\r\n".repeat(4);
+        serial.set_keep_write_on_reset(true);
+        serial.write(&banner).unwrap();
+        assert_eq!(host.read(write_ep()), None);
+        serial.reset();
+        serial.set_device_state(UsbDeviceState::Addressed, false);
+        serial.reset();
+        assert_eq!(serial.take_reset(), Some(ResetReport::default()));
+        assert_eq!(host.read(write_ep()), None);
+
+        serial.set_device_state(UsbDeviceState::Configured, false);
+        let mut received = Vec::new();
+        while serial.flush() == Err(UsbError::WouldBlock) {
+            received.extend(host.read(write_ep()).unwrap());
+        }
+        assert_eq!(received, banner);
+
+        // The packet that is being sent is still lost.
+        serial.write(b"ab").unwrap();
+        serial.write(b"cde").unwrap();
+        serial.reset();
+        assert_eq!(
+            serial.take_reset(),
+            Some(ResetReport {
+                read_discarded: 0,
+                write_discarded: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn read_line() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 72], [0u8; 8]);
+        let _dev = test_bus::device(&alloc);
+        let mut buf = [0u8; 4];
+
+        host.write(read_ep(), b"ab");
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::WouldBlock));
+
+        host.write(read_ep(), b"c\r\nd;toolong\n");
+        assert_eq!(serial.read_line(&mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(serial.read_until(b';', &mut buf), Ok(1));
+        assert_eq!(&buf[..1], b"d");
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::BufferOverflow));
+
+        // A line that fills the read buffer is discarded up to the next delimiter.
+        host.write(read_ep(), &[b'x'; 64]);
+        host.write(read_ep(), b"xx\nok\n");
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::BufferOverflow));
+        assert_eq!(serial.read_line(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"ok");
+    }
+
+    #[test]
+    fn read_line_limit() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+        let mut buf = [0u8; 128];
+
+        // Lines up to the buffer size minus the packet size are always received
+        host.write(read_ep(), &[b'x'; 64]);
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::WouldBlock));
+        host.write(read_ep(), b"\n");
+        assert_eq!(serial.read_line(&mut buf), Ok(64));
+
+        // A longer line is received if its delimiter comes with the packet that crosses the limit
+        host.write(read_ep(), &[b'x'; 64]);
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::WouldBlock));
+        host.write(read_ep(), b"x\n");
+        assert_eq!(serial.read_line(&mut buf), Ok(65));
+
+        // Otherwise it overflows, even though it would fit into the read buffer
+        host.write(read_ep(), &[b'x'; 64]);
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::WouldBlock));
+        host.write(read_ep(), b"x");
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::BufferOverflow));
+        host.write(read_ep(), b"\n");
+        assert_eq!(serial.read_line(&mut buf), Err(UsbError::WouldBlock));
+        host.write(read_ep(), b"ok\n");
+        assert_eq!(serial.read_line(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"ok");
+    }
+
+    #[test]
+    fn buffer_queries() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new_with_store(&alloc, [0u8; 128], [0u8; 8]);
+        let _dev = test_bus::device(&alloc);
+        let mut buf = [0u8; 2];
+
+        assert_eq!(serial.peek(&mut buf), Err(UsbError::WouldBlock));
+        host.write(read_ep(), b"abc");
+        assert_eq!(serial.bytes_available(), 0);
+        assert_eq!(serial.peek(&mut buf), Ok(2));
+        assert_eq!(&buf, b"ab");
+        assert_eq!(serial.bytes_available(), 3);
+        assert_eq!(serial.read(&mut buf), Ok(2));
+        assert_eq!(serial.bytes_available(), 1);
+
+        assert_eq!(serial.writable_space(), 8);
+        assert_eq!(serial.write_capacity(), 8);
+        serial.write(b"0123").unwrap();
+        serial.write(b"456").unwrap();
+        assert_eq!(serial.writable_space(), 5);
+        assert_eq!(serial.pending_tx(), 7);
+
+        assert_eq!(host.read(write_ep()).unwrap(), b"0123");
+        serial.endpoint_in_complete(write_ep());
+        assert_eq!(serial.pending_tx(), 3);
+        assert_eq!(host.read(write_ep()).unwrap(), b"456");
+        serial.endpoint_in_complete(write_ep());
+        assert_eq!(serial.pending_tx(), 0);
+    }
+
+    #[test]
+    fn pending_zlp() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let _dev = test_bus::device(&alloc);
+
+        // A full packet is followed by a zero length packet, which can't be sent yet
+        serial.write(&[0u8; 64]).unwrap();
+        serial.flush().ok();
+        assert_eq!(serial.pending_tx(), 65);
+
+        assert_eq!(host.read(write_ep()).unwrap().len(), 64);
+        serial.endpoint_in_complete(write_ep());
+        assert_eq!(serial.pending_tx(), 1);
+        assert_eq!(host.read(write_ep()).unwrap(), b"");
+        serial.endpoint_in_complete(write_ep());
+        assert_eq!(serial.pending_tx(), 0);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let (alloc, host) = TestBus::new();
+        let mut serial = SerialPort::new(&alloc);
+        let mut dev = test_bus::device(&alloc);
+
+        host.write(read_ep(), &[1; 64]);
+        host.write(read_ep(), &[2; 64]);
+        serial.fill_buf().unwrap();
+        serial.fill_buf().unwrap();
+
+        // A packet that doesn't fit is counted once when it arrives, not on every poll.
+        host.write(read_ep(), b"x");
+        serial.fill_buf().unwrap();
+        serial.endpoint_out(read_ep());
+        serial.endpoint_out(read_ep());
+        serial.fill_buf().unwrap();
+        assert_eq!(serial.stats().read_buffer_full, 1);
+
+        serial.consume(128);
+        assert_eq!(serial.fill_buf().unwrap(), b"x");
+        assert_eq!(serial.stats().packets_in, 3);
+        assert_eq!(serial.stats().bytes_in, 129);
+
+        // A full packet is followed by a ZLP.
+        serial.write(&[0; 64]).unwrap();
+        host.read(write_ep()).unwrap();
+        serial.endpoint_in_complete(write_ep());
+        assert_eq!(serial.stats().packets_out, 2);
+        assert_eq!(serial.stats().bytes_out, 64);
+        assert_eq!(serial.stats().zlps_sent, 1);
+
+        serial.write(&[0; 128]).unwrap();
+        assert_eq!(serial.write(b"z"), Err(UsbError::WouldBlock));
+        assert_eq!(serial.stats().write_would_block, 1);
+
+        serial.set_write_policy(WritePolicy::OverwriteOldest);
+        serial.write(b"abcd").unwrap();
+        assert_eq!(serial.stats().bytes_overwritten, 4);
+
+        let setup = test_bus::setup(0x21, 0x22, 0x0001, 0, 0);
+        assert!(host.control_out(&mut dev, &mut [&mut serial], setup, &[]));
+        assert!(serial.dtr());
+        assert_eq!(serial.stats().line_state_changes, 1);
+
+        serial.reset();
+        assert_eq!(serial.stats().resets, 1);
+
+        serial.clear_stats();
+        assert_eq!(serial.stats(), crate::SerialStats::default());
+    }
+}
@@ -0,0 +1,75 @@
+/// Snapshot of the statistics counters of a [`SerialPort`](crate::SerialPort)
+///
+/// The counters are only maintained if the `stats` feature is enabled, and wrap around on
+/// overflow.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct SerialStats {
+    /// Bytes received from the host
+    pub bytes_in: u32,
+
+    /// Bytes sent to the host
+    pub bytes_out: u32,
+
+    /// Packets received from the host
+    pub packets_in: u32,
+
+    /// Packets sent to the host, including zero-length packets
+    pub packets_out: u32,
+
+    /// Zero-length packets sent to terminate a transfer
+    pub zlps_sent: u32,
+
+    /// Short packets forced after a long run of full size packets
+    pub short_packets_forced: u32,
+
+    /// Calls to `write` that returned `WouldBlock` because the write buffer was full
+    pub write_would_block: u32,
+
+    /// Unsent bytes discarded by an overwriting write policy
+    pub bytes_overwritten: u32,
+
+    /// Packets that arrived while the read buffer didn't have room for them. Each packet is counted
+    /// once, however long it has to wait.
+    pub read_buffer_full: u32,
+
+    /// Bus resets
+    pub resets: u32,
+
+    /// Changes of the DTR or RTS state requested by the host
+    pub line_state_changes: u32,
+}
+
+/// Storage for the statistics counters, which is zero-sized if the `stats` feature is disabled.
+#[derive(Default)]
+pub(crate) struct Counters {
+    #[cfg(feature = "stats")]
+    stats: SerialStats,
+}
+
+impl Counters {
+    /// Updates the counters. Does nothing if the `stats` feature is disabled.
+    #[inline(always)]
+    pub fn update(&mut self, f: impl FnOnce(&mut SerialStats)) {
+        #[cfg(feature = "stats")]
+        f(&mut self.stats);
+
+        #[cfg(not(feature = "stats"))]
+        let _ = f;
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn get(&self) -> SerialStats {
+        self.stats
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn clear(&mut self) {
+        self.stats = SerialStats::default();
+    }
+}
+
+/// Increments a counter by `n` with wrap-around.
+#[inline(always)]
+pub(crate) fn add(counter: &mut u32, n: usize) {
+    *counter = counter.wrapping_add(n as u32);
+}
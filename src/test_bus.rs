@@ -0,0 +1,282 @@
+//! A host-side simulation of a `UsbBus` for unit tests.
+
+extern crate std;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+use usb_device::bus::PollResult;
+use usb_device::class_prelude::*;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::endpoint::EndpointType;
+use usb_device::{Result, UsbDirection};
+
+const MAX_ENDPOINTS: usize = 16;
+
+/// Max packet size of the control endpoints, which is the `UsbDeviceBuilder` default.
+const CONTROL_PACKET_SIZE: usize = 8;
+
+/// How many times `UsbDevice::poll` is called for each stage of a control transfer before giving
+/// up.
+const MAX_CONTROL_POLLS: usize = 16;
+
+#[derive(Default)]
+struct State {
+    next_ep: usize,
+
+    /// Packets sent by the host to OUT endpoints and not yet read by the device.
+    out: [VecDeque<Vec<u8>>; MAX_ENDPOINTS],
+
+    /// Packets written by the device to IN endpoints and not yet read by the host. Each endpoint
+    /// has room for a single packet.
+    r#in: [Option<Vec<u8>>; MAX_ENDPOINTS],
+
+    /// The first packet queued for endpoint 0 is a SETUP packet.
+    setup: bool,
+
+    /// The host has read the packet written to IN endpoint 0, and the device hasn't been told yet.
+    control_in_complete: bool,
+
+    /// Endpoint 0 has been stalled by the device.
+    control_stalled: bool,
+}
+
+/// Builds a SETUP packet.
+pub fn setup(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let mut packet = [request_type, request, 0, 0, 0, 0, 0, 0];
+    packet[2..4].copy_from_slice(&value.to_le_bytes());
+    packet[4..6].copy_from_slice(&index.to_le_bytes());
+    packet[6..8].copy_from_slice(&length.to_le_bytes());
+    packet
+}
+
+/// Host side handle of a [`TestBus`].
+#[derive(Clone, Default)]
+pub struct TestHost(Arc<Mutex<State>>);
+
+impl TestHost {
+    /// Sends a packet to an OUT endpoint.
+    pub fn write(&self, ep: EndpointAddress, data: &[u8]) {
+        self.0.lock().unwrap().out[ep.index()].push_back(data.into());
+    }
+
+    /// Receives a pending packet from an IN endpoint.
+    pub fn read(&self, ep: EndpointAddress) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().r#in[ep.index()].take()
+    }
+
+    /// Performs a control transfer with an IN data stage, polling `dev` as needed. Returns the
+    /// received data, or `None` if the device stalled the request.
+    pub fn control_in(
+        &self,
+        dev: &mut UsbDevice<'_, TestBus>,
+        classes: &mut [&mut dyn UsbClass<TestBus>],
+        setup: [u8; 8],
+    ) -> Option<Vec<u8>> {
+        self.start_control(&setup, &[]);
+        let mut data = Vec::new();
+
+        // Data stage, which ends with a short packet
+        loop {
+            let packet = self.poll_control(dev, classes)?;
+            data.extend_from_slice(&packet);
+
+            if packet.len() < CONTROL_PACKET_SIZE {
+                break;
+            }
+        }
+
+        // Status stage
+        dev.poll(classes);
+        self.write(EndpointAddress::from_parts(0, UsbDirection::Out), &[]);
+        dev.poll(classes);
+
+        Some(data)
+    }
+
+    /// Performs a control transfer with an optional OUT data stage, polling `dev` as needed.
+    /// Returns `false` if the device stalled the request.
+    pub fn control_out(
+        &self,
+        dev: &mut UsbDevice<'_, TestBus>,
+        classes: &mut [&mut dyn UsbClass<TestBus>],
+        setup: [u8; 8],
+        data: &[u8],
+    ) -> bool {
+        self.start_control(&setup, data);
+
+        // Status stage
+        match self.poll_control(dev, classes) {
+            Some(packet) => {
+                assert!(packet.is_empty(), "status stage with data");
+                dev.poll(classes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn start_control(&self, setup: &[u8], data: &[u8]) {
+        let mut state = self.0.lock().unwrap();
+
+        state.out[0].clear();
+        state.out[0].push_back(setup.into());
+        for chunk in data.chunks(CONTROL_PACKET_SIZE) {
+            state.out[0].push_back(chunk.into());
+        }
+
+        state.r#in[0] = None;
+        state.setup = true;
+        state.control_in_complete = false;
+        state.control_stalled = false;
+    }
+
+    /// Polls the device until it writes a packet to IN endpoint 0 and returns it, or returns
+    /// `None` if the device stalls the endpoint.
+    fn poll_control(
+        &self,
+        dev: &mut UsbDevice<'_, TestBus>,
+        classes: &mut [&mut dyn UsbClass<TestBus>],
+    ) -> Option<Vec<u8>> {
+        for _ in 0..MAX_CONTROL_POLLS {
+            dev.poll(classes);
+
+            let mut state = self.0.lock().unwrap();
+
+            if state.control_stalled {
+                state.out[0].clear();
+                return None;
+            }
+
+            if let Some(packet) = state.r#in[0].take() {
+                state.control_in_complete = true;
+                return Some(packet);
+            }
+        }
+
+        panic!("control transfer stuck");
+    }
+}
+
+/// Notification endpoint of the first `CdcAcmClass` allocated on a `TestBus`.
+pub fn comm_ep() -> EndpointAddress {
+    EndpointAddress::from_parts(1, UsbDirection::In)
+}
+
+/// OUT endpoint of the first `CdcAcmClass` allocated on a `TestBus`.
+pub fn read_ep() -> EndpointAddress {
+    EndpointAddress::from_parts(2, UsbDirection::Out)
+}
+
+/// IN endpoint of the first `CdcAcmClass` allocated on a `TestBus`.
+pub fn write_ep() -> EndpointAddress {
+    EndpointAddress::from_parts(3, UsbDirection::In)
+}
+
+/// Builds a device, which enables the bus. Classes must be allocated before calling this.
+pub fn device(alloc: &UsbBusAllocator<TestBus>) -> UsbDevice<'_, TestBus> {
+    UsbDeviceBuilder::new(alloc, UsbVidPid(0x16c0, 0x27dd)).build()
+}
+
+/// A `UsbBus` implementation backed by in-memory packet queues.
+pub struct TestBus(TestHost);
+
+impl TestBus {
+    pub fn new() -> (UsbBusAllocator<TestBus>, TestHost) {
+        let host = TestHost::default();
+        host.0.lock().unwrap().next_ep = 1;
+
+        (UsbBusAllocator::new(TestBus(host.clone())), host)
+    }
+}
+
+impl UsbBus for TestBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        if let Some(addr) = ep_addr {
+            return Ok(addr);
+        }
+
+        let mut state = self.0.0.lock().unwrap();
+        let index = state.next_ep;
+        state.next_ep += 1;
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut state = self.0.0.lock().unwrap();
+        let slot = &mut state.r#in[ep_addr.index()];
+
+        if slot.is_some() {
+            return Err(UsbError::WouldBlock);
+        }
+
+        *slot = Some(buf.into());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.0.0.lock().unwrap();
+
+        if ep_addr.index() == 0 {
+            state.setup = false;
+        }
+
+        let queue = &mut state.out[ep_addr.index()];
+
+        match queue.front() {
+            None => Err(UsbError::WouldBlock),
+            Some(packet) if packet.len() > buf.len() => Err(UsbError::BufferOverflow),
+            Some(_) => {
+                let packet = queue.pop_front().unwrap();
+                buf[..packet.len()].copy_from_slice(&packet);
+                Ok(packet.len())
+            }
+        }
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        if ep_addr.index() == 0 && stalled {
+            self.0.0.lock().unwrap().control_stalled = true;
+        }
+    }
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        // Only endpoint 0 is reported, the other endpoints are serviced by the tests directly.
+        let mut state = self.0.0.lock().unwrap();
+        let ep_setup = state.setup as u16;
+        let ep_out = (!state.setup && !state.out[0].is_empty()) as u16;
+        let ep_in_complete = core::mem::take(&mut state.control_in_complete) as u16;
+
+        if ep_setup | ep_out | ep_in_complete == 0 {
+            return PollResult::None;
+        }
+
+        PollResult::Data {
+            ep_out,
+            ep_in_complete,
+            ep_setup,
+        }
+    }
+}
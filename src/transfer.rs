@@ -0,0 +1,422 @@
+use crate::cdc_acm::CdcAcmClass;
+use crate::wrapper::forward_usb_class;
+use core::borrow::BorrowMut;
+use core::cmp;
+use usb_device::Result;
+use usb_device::class_prelude::*;
+
+/// Size of the length prefix of each message in a queue.
+const HEADER_LEN: usize = 2;
+
+/// Queue of length-prefixed messages in a linear store. A message can be assembled at the end of
+/// the queue before it's committed.
+struct MessageQueue<S: BorrowMut<[u8]>> {
+    store: S,
+    head: usize,
+    tail: usize,
+    partial: usize,
+}
+
+impl<S: BorrowMut<[u8]>> MessageQueue<S> {
+    fn new(store: S) -> Self {
+        MessageQueue {
+            store,
+            head: 0,
+            tail: 0,
+            partial: 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.store.borrow().len()
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+        self.partial = 0;
+    }
+
+    fn len(&self) -> usize {
+        let store = self.store.borrow();
+        let mut pos = self.head;
+        let mut count = 0;
+
+        while pos < self.tail {
+            pos += HEADER_LEN + u16::from_le_bytes([store[pos], store[pos + 1]]) as usize;
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Gets the oldest committed message.
+    fn front(&self) -> Option<&[u8]> {
+        if self.head == self.tail {
+            return None;
+        }
+
+        let store = self.store.borrow();
+        let len = u16::from_le_bytes([store[self.head], store[self.head + 1]]) as usize;
+        let start = self.head + HEADER_LEN;
+
+        Some(&store[start..start + len])
+    }
+
+    /// Removes the oldest committed message.
+    fn pop(&mut self) {
+        if let Some(len) = self.front().map(<[u8]>::len) {
+            self.head += HEADER_LEN + len;
+        }
+    }
+
+    /// Reserves `len` bytes at the end of the partial message, moving the queue to the start of
+    /// the store if necessary.
+    fn reserve(&mut self, len: usize) -> Option<&mut [u8]> {
+        let start = self.tail + HEADER_LEN + self.partial;
+
+        if start + len > self.capacity() {
+            if self.head == 0 || start - self.head + len > self.capacity() {
+                return None;
+            }
+
+            self.store.borrow_mut().copy_within(self.head..start, 0);
+            self.tail -= self.head;
+            self.head = 0;
+        }
+
+        let start = self.tail + HEADER_LEN + self.partial;
+        Some(&mut self.store.borrow_mut()[start..start + len])
+    }
+
+    /// Returns `true` if `len` more bytes could be reserved after all committed messages are
+    /// removed.
+    fn fits(&self, len: usize) -> bool {
+        HEADER_LEN + self.partial + len <= self.capacity()
+    }
+
+    /// Adds `len` bytes from the reserved space to the partial message.
+    fn extend(&mut self, len: usize) {
+        self.partial += len;
+    }
+
+    /// Commits the partial message.
+    fn commit(&mut self) {
+        let header = (self.partial as u16).to_le_bytes();
+        self.store.borrow_mut()[self.tail..self.tail + HEADER_LEN].copy_from_slice(&header);
+        self.tail += HEADER_LEN + self.partial;
+        self.partial = 0;
+    }
+
+    /// Discards the partial message.
+    fn abort(&mut self) {
+        self.partial = 0;
+    }
+}
+
+/// Message oriented port over a [`CdcAcmClass`] that preserves USB transfer boundaries.
+///
+/// Each message passed to [`send`](TransferPort::send) is sent as one transfer, which ends with a
+/// short packet or a zero-length packet if its length is a multiple of the max packet size.
+/// Received transfers are reassembled into whole messages, so one transfer up to and including its
+/// short packet is returned by [`recv`](TransferPort::recv) as one message.
+///
+/// Messages are queued in the caller-supplied transmit and receive stores with a 2 byte length
+/// prefix each, so the stores limit the total size of queued messages. The receive store must have
+/// room for at least one max size packet and the prefix. When the receive store is full, packets
+/// are left in the endpoint until messages are received. Each packet is read into the receive
+/// store directly, so received messages are dropped and counted if the rest of the store is
+/// smaller than one more max size packet after the message and its prefix.
+///
+/// Packets are moved between the endpoints and the stores by [`send`](TransferPort::send) and
+/// [`recv`](TransferPort::recv), and by `UsbDevice::poll` as soon as the host has sent or taken a
+/// packet, if the port is passed to it instead of the class.
+pub struct TransferPort<'a, B, TS, RS>
+where
+    B: UsbBus,
+    TS: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+{
+    class: CdcAcmClass<'a, B>,
+    tx: MessageQueue<TS>,
+    tx_offset: usize,
+    rx: MessageQueue<RS>,
+    rx_discarding: bool,
+    dropped: u32,
+}
+
+impl<'a, B, TS, RS> TransferPort<'a, B, TS, RS>
+where
+    B: UsbBus,
+    TS: BorrowMut<[u8]>,
+    RS: BorrowMut<[u8]>,
+{
+    /// Creates a new transfer port with the given transmit and receive stores.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the receive store is smaller than the max packet size of the class plus 2 bytes.
+    pub fn new(class: CdcAcmClass<'a, B>, tx_store: TS, rx_store: RS) -> Self {
+        assert!(rx_store.borrow().len() >= class.max_packet_size() as usize + HEADER_LEN);
+
+        TransferPort {
+            class,
+            tx: MessageQueue::new(tx_store),
+            tx_offset: 0,
+            rx: MessageQueue::new(rx_store),
+            rx_discarding: false,
+            dropped: 0,
+        }
+    }
+
+    /// Gets the class.
+    pub fn class(&self) -> &CdcAcmClass<'a, B> {
+        &self.class
+    }
+
+    /// Mutably gets the class. Reading or writing packets directly will break message boundaries.
+    pub fn class_mut(&mut self) -> &mut CdcAcmClass<'a, B> {
+        &mut self.class
+    }
+
+    /// Destroys the transfer port and returns the class and the transmit and receive stores.
+    pub fn release(self) -> (CdcAcmClass<'a, B>, TS, RS) {
+        (self.class, self.tx.store, self.rx.store)
+    }
+
+    /// Gets the number of received messages that were dropped because they didn't fit into the
+    /// receive store.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Gets the number of messages waiting to be sent, including one that's partially sent. Unlike
+    /// [`SerialPort::pending_tx`](crate::SerialPort::pending_tx), this counts messages rather than
+    /// bytes.
+    pub fn pending_tx(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Queues a message to be sent as one transfer.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - There is not enough room in the
+    ///   transmit store. Nothing was queued.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - The message is larger than the
+    ///   whole transmit store, or longer than 65535 bytes.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn send(&mut self, message: &[u8]) -> Result<()> {
+        if message.len() > u16::MAX as usize || !self.tx.fits(message.len()) {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        let Some(buf) = self.tx.reserve(message.len()) else {
+            return Err(UsbError::WouldBlock);
+        };
+
+        buf.copy_from_slice(message);
+        self.tx.extend(message.len());
+        self.tx.commit();
+
+        match self.flush() {
+            Ok(()) | Err(UsbError::WouldBlock) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Receives a message into `data` and returns its length.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No message has been received.
+    /// * [`BufferOverflow`](usb_device::UsbError::BufferOverflow) - `data` is too small for the
+    ///   message. The message is kept in the queue.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn recv(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.poll_rx()?;
+
+        let Some(message) = self.rx.front() else {
+            return Err(UsbError::WouldBlock);
+        };
+
+        let len = message.len();
+        data.get_mut(..len)
+            .ok_or(UsbError::BufferOverflow)?
+            .copy_from_slice(message);
+
+        self.rx.pop();
+        Ok(len)
+    }
+
+    /// Passes the oldest received message to `f` without copying it, removes the message from the
+    /// queue and returns the result of `f`.
+    ///
+    /// # Errors
+    ///
+    /// * [`WouldBlock`](usb_device::UsbError::WouldBlock) - No message has been received. `f` is
+    ///   not called.
+    ///
+    /// Other errors from `usb-device` may also be propagated.
+    pub fn recv_with<T>(&mut self, f: impl FnOnce(&[u8]) -> T) -> Result<T> {
+        self.poll_rx()?;
+
+        let Some(message) = self.rx.front() else {
+            return Err(UsbError::WouldBlock);
+        };
+
+        let result = f(message);
+        self.rx.pop();
+
+        Ok(result)
+    }
+
+    /// Sends as many queued messages as possible. Returns `Ok` if all messages have been written to
+    /// the endpoint, and `Err(WouldBlock)` if some are still queued.
+    pub fn flush(&mut self) -> Result<()> {
+        let max_packet_size = self.class.max_packet_size() as usize;
+
+        while let Some(message) = self.tx.front() {
+            let end = cmp::min(self.tx_offset + max_packet_size, message.len());
+            let packet = &message[self.tx_offset..end];
+
+            // This may return WouldBlock which will be propagated.
+            self.class.write_packet(packet)?;
+
+            if packet.len() < max_packet_size {
+                // The short packet or ZLP ends the transfer.
+                self.tx.pop();
+                self.tx_offset = 0;
+            } else {
+                self.tx_offset = end;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads packets from the endpoint into the receive queue while there's room.
+    fn poll_rx(&mut self) -> Result<()> {
+        let max_packet_size = self.class.max_packet_size() as usize;
+
+        loop {
+            if !self.rx_discarding && !self.rx.fits(max_packet_size) {
+                // The message would never fit, so drop the rest of the transfer.
+                self.rx.abort();
+                self.rx_discarding = true;
+                self.dropped = self.dropped.wrapping_add(1);
+            }
+
+            let Some(buf) = self.rx.reserve(max_packet_size) else {
+                return Ok(());
+            };
+
+            let count = match self.class.read_packet(buf) {
+                Ok(count) => count,
+                Err(UsbError::WouldBlock) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            if !self.rx_discarding {
+                self.rx.extend(count);
+            }
+
+            if count < max_packet_size {
+                if self.rx_discarding {
+                    self.rx_discarding = false;
+                } else {
+                    self.rx.commit();
+                }
+            }
+        }
+    }
+}
+
+forward_usb_class! {
+    impl<B, TS, RS> UsbClass<B> for TransferPort<'_, B, TS, RS>
+    where
+        B: UsbBus,
+        TS: BorrowMut<[u8]>,
+        RS: BorrowMut<[u8]>,
+    {
+        self.class;
+
+        fn reset(&mut self) {
+            self.tx.clear();
+            self.tx_offset = 0;
+            self.rx.clear();
+            self.rx_discarding = false;
+        }
+
+        fn endpoint_out(&mut self, addr) {
+            if addr == self.class.read_ep().address() {
+                self.poll_rx().ok();
+            }
+        }
+
+        fn endpoint_in_complete(&mut self, addr) {
+            if addr == self.class.write_ep().address() {
+                self.flush().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::{self, TestBus, read_ep, write_ep};
+
+    #[test]
+    fn send() {
+        let (alloc, host) = TestBus::new();
+        let mut port = TransferPort::new(CdcAcmClass::new(&alloc, 8), [0u8; 32], [0u8; 16]);
+        let _dev = test_bus::device(&alloc);
+
+        port.send(b"0123456789").unwrap();
+        port.send(b"abcdefgh").unwrap();
+        port.send(b"").unwrap();
+        assert_eq!(port.pending_tx(), 3);
+        assert_eq!(port.send(b"0123456789"), Err(UsbError::WouldBlock));
+        assert_eq!(port.send(&[0; 31]), Err(UsbError::BufferOverflow));
+
+        let mut packets = [&b"01234567"[..], b"89", b"abcdefgh", b"", b""].into_iter();
+        while let Some(packet) = host.read(write_ep()) {
+            assert_eq!(packet, packets.next().unwrap());
+            port.flush().ok();
+        }
+
+        assert_eq!(packets.next(), None);
+        assert_eq!(port.pending_tx(), 0);
+    }
+
+    #[test]
+    fn recv() {
+        let (alloc, host) = TestBus::new();
+        let mut port = TransferPort::new(CdcAcmClass::new(&alloc, 8), [0u8; 16], [0u8; 24]);
+        let _dev = test_bus::device(&alloc);
+
+        for packet in [&b"01234567"[..], b"89", b"", b"abc"] {
+            host.write(read_ep(), packet);
+        }
+
+        let mut buf = [0u8; 16];
+        assert_eq!(port.recv(&mut buf[..4]), Err(UsbError::BufferOverflow));
+        assert_eq!(port.recv(&mut buf), Ok(10));
+        assert_eq!(&buf[..10], b"0123456789");
+        assert_eq!(port.recv(&mut buf), Ok(0));
+        assert_eq!(port.recv_with(|message| message == b"abc"), Ok(true));
+        assert_eq!(port.recv(&mut buf), Err(UsbError::WouldBlock));
+
+        // A message that can never fit is dropped
+        for packet in [&b"01234567"[..], b"01234567", b"0", b"ok"] {
+            host.write(read_ep(), packet);
+        }
+
+        assert_eq!(port.recv(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"ok");
+        assert_eq!(port.dropped(), 1);
+    }
+}
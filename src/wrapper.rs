@@ -0,0 +1,91 @@
+/// Implements `UsbClass` for a type that wraps a class in one of its fields, by forwarding every
+/// method to that class.
+///
+/// `reset`, `endpoint_out` and `endpoint_in_complete` may be given bodies, which run after the
+/// wrapped class has handled the call, so that the wrapper can clear or update its own state.
+macro_rules! forward_usb_class {
+    (
+        impl<$($generic:ident),*> UsbClass<$bus:ident> for $ty:ty
+        where
+            $($param:ident: $bound:path,)*
+        {
+            self.$field:ident;
+            $(fn reset(&mut $reset_self:ident) $reset:block)?
+            $(fn endpoint_out(&mut $out_self:ident, $out_addr:ident) $out:block)?
+            $(fn endpoint_in_complete(&mut $in_self:ident, $in_addr:ident) $in_complete:block)?
+        }
+    ) => {
+        impl<$($generic),*> ::usb_device::class_prelude::UsbClass<$bus> for $ty
+        where
+            $($param: $bound,)*
+        {
+            fn get_configuration_descriptors(
+                &self,
+                writer: &mut ::usb_device::class_prelude::DescriptorWriter,
+            ) -> ::usb_device::Result<()> {
+                self.$field.get_configuration_descriptors(writer)
+            }
+
+            fn get_bos_descriptors(
+                &self,
+                writer: &mut ::usb_device::class_prelude::BosWriter,
+            ) -> ::usb_device::Result<()> {
+                self.$field.get_bos_descriptors(writer)
+            }
+
+            fn get_string(
+                &self,
+                index: ::usb_device::class_prelude::StringIndex,
+                lang_id: ::usb_device::descriptor::lang_id::LangID,
+            ) -> Option<&str> {
+                self.$field.get_string(index, lang_id)
+            }
+
+            $crate::wrapper::forward_usb_class!(@reset $field $($reset_self $reset)?);
+
+            $crate::wrapper::forward_usb_class!(
+                @endpoint endpoint_out $field $($out_self $out_addr $out)?
+            );
+
+            $crate::wrapper::forward_usb_class!(
+                @endpoint endpoint_in_complete $field $($in_self $in_addr $in_complete)?
+            );
+
+            fn control_in(&mut self, xfer: ::usb_device::class_prelude::ControlIn<$bus>) {
+                self.$field.control_in(xfer);
+            }
+
+            fn control_out(&mut self, xfer: ::usb_device::class_prelude::ControlOut<$bus>) {
+                self.$field.control_out(xfer);
+            }
+        }
+    };
+
+    (@reset $field:ident) => {
+        fn reset(&mut self) {
+            self.$field.reset();
+        }
+    };
+
+    (@reset $field:ident $self_:ident $body:block) => {
+        fn reset(&mut $self_) {
+            $self_.$field.reset();
+            $body
+        }
+    };
+
+    (@endpoint $method:ident $field:ident) => {
+        fn $method(&mut self, addr: ::usb_device::class_prelude::EndpointAddress) {
+            self.$field.$method(addr);
+        }
+    };
+
+    (@endpoint $method:ident $field:ident $self_:ident $addr:ident $body:block) => {
+        fn $method(&mut $self_, $addr: ::usb_device::class_prelude::EndpointAddress) {
+            $self_.$field.$method($addr);
+            $body
+        }
+    };
+}
+
+pub(crate) use forward_usb_class;